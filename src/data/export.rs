@@ -0,0 +1,218 @@
+use anyhow::{Result, anyhow};
+use serde_json::{Map, Value};
+use sqlx::{Column, Row};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::data::init::Storage;
+use crate::data::query::{column_value, typed_value};
+use crate::load::parse_data::{DataFile, Row as DataRow};
+use crate::load::parse_tables::TableConfig;
+
+/// Layout of exported files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// Compact `data.json`, one array of rows per table
+    Json,
+    /// Same as `Json`, indented
+    Pretty,
+    /// One `<table>.csv` file per table
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "pretty" => Ok(Self::Pretty),
+            "csv" => Ok(Self::Csv),
+            other => Err(anyhow!(
+                "Unknown export format `{other}`, expected json, pretty or csv"
+            )),
+        }
+    }
+}
+
+/// Column names and rows of an exported table or query result
+pub struct Export {
+    pub columns: Vec<String>,
+    pub rows: Vec<DataRow>,
+}
+
+impl Storage {
+    /// Rows of a table ordered by primary key, stored columns in schema order.
+    /// NULL columns are left out as in `data.json`.
+    pub async fn export_table(&self, table: &TableConfig) -> Result<Export> {
+        let columns: Vec<_> = table.columns.iter().filter(|c| !c.is_computed()).collect();
+        let sql = format!(
+            "SELECT {} FROM {} ORDER BY {}",
+            columns
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            table.name,
+            table.primary_key
+        );
+
+        let rows = sqlx::query(&sql)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| {
+                columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| (c.name.clone(), typed_value(row, i, &c.col_type)))
+                    .filter(|(_, v)| !v.is_null())
+                    .collect()
+            })
+            .collect();
+
+        Ok(Export {
+            columns: columns.iter().map(|c| c.name.clone()).collect(),
+            rows,
+        })
+    }
+
+    /// Rows of every table as exported, in the shape of a loaded `data.json`
+    pub async fn data(&self) -> Result<DataFile> {
+        let mut data = DataFile::new();
+        for table in &self.schema.tables {
+            data.insert(table.name.clone(), self.export_table(table).await?.rows);
+        }
+        Ok(data)
+    }
+
+    /// Rows of a query result, columns in select order
+    pub async fn export_query(&self, query: &str) -> Result<Export> {
+        let result = sqlx::query(query).fetch_all(&self.pool).await?;
+        let columns = match result.first() {
+            Some(row) => row.columns().iter().map(|c| c.name().to_string()).collect(),
+            None => Vec::new(),
+        };
+
+        let rows = result
+            .iter()
+            .map(|row| {
+                columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| (c.clone(), column_value(row, i)))
+                    .filter(|(_, v)| !v.is_null())
+                    .collect()
+            })
+            .collect();
+
+        Ok(Export { columns, rows })
+    }
+
+    /// Writes every table to `output`, a file for JSON or a directory for CSV
+    pub async fn export(&self, format: ExportFormat, output: &Path) -> Result<()> {
+        let mut tables = Vec::new();
+        for table in &self.schema.tables {
+            tables.push((table.name.as_str(), self.export_table(table).await?));
+        }
+
+        match format {
+            ExportFormat::Csv => {
+                std::fs::create_dir_all(output).map_err(|e| anyhow!("{:?}: {e}", output))?;
+                for (name, export) in &tables {
+                    write_csv(export, &output.join(format!("{name}.csv")))?;
+                }
+            }
+            _ => {
+                let doc: Map<String, Value> = tables
+                    .into_iter()
+                    .map(|(name, export)| (name.to_string(), Value::from(export.rows)))
+                    .collect();
+                write_json(&Value::Object(doc), format, output)?;
+            }
+        }
+
+        log::info!(
+            "Exported {} tables to {:?}",
+            self.schema.tables.len(),
+            output
+        );
+        Ok(())
+    }
+}
+
+/// Writes a query result as a JSON array or a single CSV file
+pub fn write_export(export: Export, format: ExportFormat, output: &Path) -> Result<()> {
+    match format {
+        ExportFormat::Csv => write_csv(&export, output),
+        _ => write_json(&Value::from(export.rows), format, output),
+    }
+}
+
+fn write_json(doc: &Value, format: ExportFormat, output: &Path) -> Result<()> {
+    let json = match format {
+        ExportFormat::Pretty => serde_json::to_string_pretty(doc)?,
+        _ => serde_json::to_string(doc)?,
+    };
+    std::fs::write(output, json + "\n").map_err(|e| anyhow!("{:?}: {e}", output))
+}
+
+fn write_csv(export: &Export, output: &Path) -> Result<()> {
+    let mut csv = csv::Writer::from_path(output).map_err(|e| anyhow!("{:?}: {e}", output))?;
+    csv.write_record(&export.columns)?;
+    for row in &export.rows {
+        csv.write_record(export.columns.iter().map(|c| match row.get(c) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s.clone(),
+            Some(other) => other.to_string(),
+        }))?;
+    }
+    csv.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load::parse_csv::parse_csv;
+    use crate::load::parse_tables::SchemaConfig;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn exports_in_schema_and_key_order() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [{
+                "name": "io",
+                "primary_key": "id",
+                "columns": [
+                    { "name": "id", "type": "text" },
+                    { "name": "rack", "type": "int" },
+                    { "name": "spare", "type": "bool" },
+                    { "name": "label", "type": "text", "expr": "id || '!'" }
+                ]
+            }]
+        }))
+        .unwrap();
+        let data = serde_json::from_value(json!({
+            "io": [{ "spare": true, "id": "B", "rack": 1 }, { "id": "A", "rack": 0, "spare": false }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema.clone(), data).await.unwrap();
+
+        let export = storage.export_table(&schema.tables[0]).await.unwrap();
+        assert_eq!(export.columns, ["id", "rack", "spare"]);
+        assert_eq!(
+            serde_json::to_string(&export.rows).unwrap(),
+            r#"[{"id":"A","rack":0,"spare":false},{"id":"B","rack":1,"spare":true}]"#
+        );
+
+        let path = std::env::temp_dir().join(format!("traverse_export_{}.csv", std::process::id()));
+        write_csv(&export, &path).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(csv, "id,rack,spare\nA,0,false\nB,1,true\n");
+
+        let (rows, _) = parse_csv(csv.as_bytes(), &schema.tables[0]).unwrap();
+        assert_eq!(rows, export.rows);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{Result, anyhow};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use sqlparser::ast::{
+    Expr, Ident, Query, SelectItem, SetExpr, Statement, TableAlias, TableFactor, TableWithJoins,
+};
+use sqlparser::dialect::SQLiteDialect;
+use sqlparser::parser::Parser;
+
+/// Name of the column holding the root's primary key in `FetchRoot::select_key`
+pub const ROOT_KEY: &str = "scope_root_key";
+
+/// The table a fetch statement takes its rows from, the first table of its FROM clause
+#[derive(Debug)]
+pub struct FetchRoot {
+    pub table: String,
+    /// Name the root's columns are qualified with, `None` for an unnamed subquery
+    pub qualifier: Option<String>,
+    query: Box<Query>,
+}
+
+/// A single table in parentheses, `from(valves)`, which SQLite accepts and sqlparser does not
+static PARENTHESIZED_TABLE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)\b(from|join)\s*\(\s*([A-Za-z_][A-Za-z0-9_]*|"[^"]+")\s*\)"#)
+        .expect("valid regex")
+});
+
+impl FetchRoot {
+    pub fn parse(fetch: &str) -> Result<Self> {
+        let query = parse_query(fetch)?;
+        let (table, qualifier) = root_of(&query)?;
+        Ok(Self {
+            table,
+            qualifier,
+            query,
+        })
+    }
+
+    /// The fetch statement with the root's primary key appended as `ROOT_KEY`
+    pub fn select_key(&self, pk: &str) -> String {
+        let mut query = self.query.clone();
+        if let SetExpr::Select(select) = query.body.as_mut() {
+            let expr = match &self.qualifier {
+                Some(q) => Expr::CompoundIdentifier(vec![Ident::new(q), Ident::new(pk)]),
+                None => Expr::Identifier(Ident::new(pk)),
+            };
+            select.projection.push(SelectItem::ExprWithAlias {
+                expr,
+                alias: Ident::new(ROOT_KEY),
+            });
+        }
+        query.to_string()
+    }
+}
+
+/// Name of the root table of a fetch statement
+pub fn root_table_name(query: &str) -> Option<String> {
+    FetchRoot::parse(query).ok().map(|r| r.table)
+}
+
+/// Tables a SELECT reads from, in FROM and JOIN order, subqueries included.
+/// Empty when the statement cannot be parsed.
+pub fn source_tables(sql: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    if let Ok(query) = parse_query(sql) {
+        set_tables(&query.body, &mut out);
+    }
+    out
+}
+
+fn parse_query(sql: &str) -> Result<Box<Query>> {
+    let mut statements = Parser::parse_sql(&SQLiteDialect {}, sql)
+        .or_else(|e| {
+            // Only rewritten when needed, the pattern could also match inside a string literal
+            let unwrapped = PARENTHESIZED_TABLE.replace_all(sql, "$1 $2");
+            match unwrapped != sql {
+                true => Parser::parse_sql(&SQLiteDialect {}, &unwrapped),
+                false => Err(e),
+            }
+        })
+        .map_err(|e| anyhow!("Cannot parse fetch statement: {e}"))?;
+    match (statements.pop(), statements.is_empty()) {
+        (Some(Statement::Query(query)), true) => Ok(query),
+        _ => Err(anyhow!("Fetch must be a single SELECT statement")),
+    }
+}
+
+fn set_tables(body: &SetExpr, out: &mut Vec<String>) {
+    match body {
+        SetExpr::Select(select) => {
+            for from in &select.from {
+                join_tables(from, out);
+            }
+        }
+        SetExpr::Query(query) => set_tables(&query.body, out),
+        SetExpr::SetOperation { left, right, .. } => {
+            set_tables(left, out);
+            set_tables(right, out);
+        }
+        _ => {}
+    }
+}
+
+fn join_tables(from: &TableWithJoins, out: &mut Vec<String>) {
+    for factor in std::iter::once(&from.relation).chain(from.joins.iter().map(|j| &j.relation)) {
+        match factor {
+            TableFactor::Table { name, .. } => out.extend(name.0.last().map(|i| i.value.clone())),
+            TableFactor::Derived { subquery, .. } => set_tables(&subquery.body, out),
+            TableFactor::NestedJoin {
+                table_with_joins, ..
+            } => join_tables(table_with_joins, out),
+            _ => {}
+        }
+    }
+}
+
+fn root_of(query: &Query) -> Result<(String, Option<String>)> {
+    let SetExpr::Select(select) = query.body.as_ref() else {
+        return Err(anyhow!("Fetch must be a plain SELECT, not a set operation"));
+    };
+    let from = select
+        .from
+        .first()
+        .ok_or_else(|| anyhow!("Fetch has no FROM clause, cannot determine the root table"))?;
+    factor_root(&from.relation)
+}
+
+fn factor_root(factor: &TableFactor) -> Result<(String, Option<String>)> {
+    let alias = |a: &Option<TableAlias>| a.as_ref().map(|a| a.name.value.clone());
+    match factor {
+        TableFactor::Table { name, alias: a, .. } => {
+            let table = name
+                .0
+                .last()
+                .map(|i| i.value.clone())
+                .ok_or_else(|| anyhow!("Empty table name in fetch"))?;
+            let qualifier = alias(a).unwrap_or_else(|| table.clone());
+            Ok((table, Some(qualifier)))
+        }
+        TableFactor::Derived {
+            subquery, alias: a, ..
+        } => {
+            let (table, _) = root_of(subquery)?;
+            Ok((table, alias(a)))
+        }
+        TableFactor::NestedJoin {
+            table_with_joins,
+            alias: a,
+        } => {
+            let (table, qualifier) = factor_root(&table_with_joins.relation)?;
+            Ok((table, alias(a).or(qualifier)))
+        }
+        other => Err(anyhow!(
+            "Cannot determine the root table, `{other}` is not a table"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_root_table_and_qualifier() {
+        let root = |sql: &str| {
+            let r = FetchRoot::parse(sql).unwrap();
+            (r.table, r.qualifier)
+        };
+        let some = |t: &str, q: &str| (t.to_string(), Some(q.to_string()));
+
+        assert_eq!(root("SELECT * FROM valves;"), some("valves", "valves"));
+        assert_eq!(
+            root("select v.* from valves v join io on io.id = v.open_feedback where io.rack = 0"),
+            some("valves", "v")
+        );
+        assert_eq!(root("SELECT * FROM \"valves\" AS x"), some("valves", "x"));
+        assert_eq!(root("SELECT * from(valves)"), some("valves", "valves"));
+        assert_eq!(
+            root("SELECT * FROM ( valves ) v JOIN (io) ON io.id = v.open_feedback"),
+            some("valves", "v")
+        );
+        assert_eq!(
+            root("SELECT * from(valves join io on io.id = valves.open_feedback)"),
+            some("valves", "valves")
+        );
+        assert_eq!(
+            root("SELECT * FROM (SELECT * FROM io WHERE rack = 1) r"),
+            some("io", "r")
+        );
+        assert_eq!(
+            root("SELECT * FROM (SELECT * FROM io)"),
+            ("io".to_string(), None)
+        );
+
+        for bad in [
+            "SELECT 1",
+            "SELECT * FROM io UNION SELECT * FROM io",
+            "DELETE FROM io",
+            "SELECT * FROM",
+        ] {
+            assert!(FetchRoot::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn appends_qualified_key() {
+        let root = FetchRoot::parse("SELECT *, rack * 100 AS n FROM io i ORDER BY n").unwrap();
+        assert_eq!(
+            root.select_key("id"),
+            "SELECT *, rack * 100 AS n, i.id AS scope_root_key FROM io AS i ORDER BY n"
+        );
+    }
+
+    #[test]
+    fn lists_source_tables() {
+        assert_eq!(
+            source_tables(
+                "SELECT v.name, io.spare FROM valves v JOIN (SELECT * FROM io) io ON io.id = v.fb \
+                 UNION SELECT name, 0 FROM from_racks"
+            ),
+            ["valves", "io", "from_racks"]
+        );
+        assert!(source_tables("not sql").is_empty());
+    }
+}
@@ -0,0 +1,580 @@
+use serde_json::Map;
+use serde_json::Value;
+use sqlx::query::Query;
+use sqlx::sqlite::SqliteArguments;
+use sqlx::{Row, Sqlite};
+use std::collections::{HashMap, HashSet};
+
+use crate::data::init::Storage;
+use crate::data::query::{column_value, typed_value};
+use crate::data::scope::key_text;
+use crate::load::parse_tables::{ForeignKeyConfig, TableConfig};
+
+/// A row identified by its table and primary key value
+pub type RowKey = (String, Value);
+
+/// A row referencing another through `column`, `depth` hops away from the row asked about
+#[derive(Debug)]
+pub struct Reference {
+    pub depth: usize,
+    pub table: String,
+    pub column: String,
+    pub key: Value,
+}
+
+pub(super) const CHUNK_SIZE: usize = 500;
+
+impl Storage {
+    /// Parses `table:key`, typing the key after the table's primary key column
+    pub fn row_key(&self, spec: &str) -> anyhow::Result<RowKey> {
+        let (table, key) = spec
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Expected <table>:<key>, got '{}'", spec))?;
+        let node = self
+            .node(table)
+            .ok_or_else(|| anyhow::anyhow!("Table '{}' not found in schema", table))?;
+        let key = match self
+            .column(table, &node.primary_key)
+            .map(|c| c.col_type.as_str())
+        {
+            Some("int") => Value::from(key.parse::<i64>()?),
+            Some("float") => serde_json::from_str(key)?,
+            _ => Value::from(key),
+        };
+        Ok((node.name.clone(), key))
+    }
+
+    /// Keys of the rows referencing one of `keys` in `table`, per foreign key pointing at it
+    pub async fn referencing_rows(
+        &self,
+        table: &str,
+        keys: &[Value],
+    ) -> anyhow::Result<Vec<(&TableConfig, &ForeignKeyConfig, Vec<Value>)>> {
+        let target = self
+            .node(table)
+            .ok_or_else(|| anyhow::anyhow!("Table '{}' not found in schema", table))?;
+
+        let mut out = Vec::new();
+        for (src, fk) in self.edges().filter(|(_, fk)| fk.references.table == table) {
+            let mut found = Vec::new();
+            for chunk in keys.chunks(CHUNK_SIZE) {
+                let sql = format!(
+                    "SELECT DISTINCT src.{} FROM {} src JOIN {} t ON t.{} = src.{} WHERE t.{} IN ({}) ORDER BY 1",
+                    src.primary_key,
+                    src.name,
+                    target.name,
+                    fk.references.column,
+                    fk.column,
+                    target.primary_key,
+                    vec!["?"; chunk.len()].join(", ")
+                );
+                let mut query = sqlx::query(&sql);
+                for key in chunk {
+                    query = bind_value(query, key);
+                }
+                for row in query.fetch_all(&self.pool).await? {
+                    found.push(column_value(&row, 0));
+                }
+            }
+            if !found.is_empty() {
+                out.push((src, fk, found));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Every row reaching one of `start` by following foreign keys, including `start` itself
+    pub async fn reverse_closure(&self, start: Vec<RowKey>) -> anyhow::Result<HashSet<RowKey>> {
+        let mut seen: HashSet<RowKey> = start.iter().cloned().collect();
+        let mut frontier = start;
+
+        while !frontier.is_empty() {
+            let mut tables: Vec<String> = frontier.iter().map(|(t, _)| t.clone()).collect();
+            tables.sort();
+            tables.dedup();
+
+            let mut next = Vec::new();
+            for table in tables {
+                let keys: Vec<Value> = frontier
+                    .iter()
+                    .filter(|(t, _)| *t == table)
+                    .map(|(_, k)| k.clone())
+                    .collect();
+                for (src, _, found) in self.referencing_rows(&table, &keys).await? {
+                    for key in found {
+                        let row = (src.name.clone(), key);
+                        if seen.insert(row.clone()) {
+                            next.push(row);
+                        }
+                    }
+                }
+            }
+            frontier = next;
+        }
+        Ok(seen)
+    }
+
+    /// Rows `start` refers to through the foreign keys of tables, followed `depth` levels,
+    /// including `start` itself. These are the rows listing one of `start` in their children.
+    pub async fn referenced_rows(
+        &self,
+        start: &[RowKey],
+        depth: usize,
+    ) -> anyhow::Result<Vec<RowKey>> {
+        let mut seen: HashSet<RowKey> = start.iter().cloned().collect();
+        let mut frontier = start.to_vec();
+
+        for _ in 0..depth {
+            let mut next = Vec::new();
+            for (src, fk) in self.edges().filter(|(src, _)| !self.is_view(&src.name)) {
+                let keys: Vec<&Value> = frontier
+                    .iter()
+                    .filter(|(t, _)| *t == src.name)
+                    .map(|(_, k)| k)
+                    .collect();
+                let Some(target) = self.node(&fk.references.table) else {
+                    continue;
+                };
+                for chunk in keys.chunks(CHUNK_SIZE) {
+                    let sql = format!(
+                        "SELECT DISTINCT t.{} FROM {} src JOIN {} t ON t.{} = src.{} WHERE src.{} IN ({})",
+                        target.primary_key,
+                        src.name,
+                        target.name,
+                        fk.references.column,
+                        fk.column,
+                        src.primary_key,
+                        vec!["?"; chunk.len()].join(", ")
+                    );
+                    let mut query = sqlx::query(&sql);
+                    for key in chunk {
+                        query = bind_value(query, key);
+                    }
+                    for row in query.fetch_all(&self.pool).await? {
+                        let row = (target.name.clone(), column_value(&row, 0));
+                        if seen.insert(row.clone()) {
+                            next.push(row);
+                        }
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
+        }
+        Ok(seen.into_iter().collect())
+    }
+}
+
+impl Storage {
+    /// Rows referencing `row`, transitively, in depth first order.
+    /// A row reached twice is listed again but not expanded again. Views only show rows
+    /// of their tables again, so they are left out.
+    pub async fn where_used(&self, row: RowKey) -> anyhow::Result<Vec<Reference>> {
+        let mut seen: HashSet<RowKey> = HashSet::from([row.clone()]);
+        let mut stack = self.references_to(&row, 1).await?;
+        stack.reverse();
+        let mut out = Vec::new();
+
+        while let Some(reference) = stack.pop() {
+            let row = (reference.table.clone(), reference.key.clone());
+            let depth = reference.depth;
+            out.push(reference);
+            if seen.insert(row.clone()) {
+                let mut children = self.references_to(&row, depth + 1).await?;
+                children.reverse();
+                stack.extend(children);
+            }
+        }
+        Ok(out)
+    }
+
+    async fn references_to(&self, row: &RowKey, depth: usize) -> anyhow::Result<Vec<Reference>> {
+        let mut out = Vec::new();
+        for (src, fk, keys) in self
+            .referencing_rows(&row.0, std::slice::from_ref(&row.1))
+            .await?
+            .into_iter()
+            .filter(|(src, _, _)| !self.is_view(&src.name))
+        {
+            out.extend(keys.into_iter().map(|key| Reference {
+                depth,
+                table: src.name.clone(),
+                column: fk.column.clone(),
+                key,
+            }));
+        }
+        Ok(out)
+    }
+
+    /// Rows of `table` that no foreign key of a table points at,
+    /// `None` when nothing references the table at all
+    pub async fn unreferenced_rows(
+        &self,
+        table: &TableConfig,
+    ) -> anyhow::Result<Option<Vec<Value>>> {
+        let conditions: Vec<String> = self
+            .edges()
+            .filter(|(src, fk)| fk.references.table == table.name && !self.is_view(&src.name))
+            .map(|(src, fk)| {
+                format!(
+                    "NOT EXISTS (SELECT 1 FROM {} s WHERE s.{} = t.{})",
+                    src.name, fk.column, fk.references.column
+                )
+            })
+            .collect();
+        if conditions.is_empty() {
+            return Ok(None);
+        }
+
+        let sql = format!(
+            "SELECT t.{} FROM {} t WHERE {} ORDER BY t.{}",
+            table.primary_key,
+            table.name,
+            conditions.join(" AND "),
+            table.primary_key
+        );
+        let rows = sqlx::query(&sql).fetch_all(&self.pool).await?;
+        Ok(Some(rows.iter().map(|r| column_value(r, 0)).collect()))
+    }
+}
+
+impl Storage {
+    /// Rows referencing each of `keys` in `table`, followed `depth` levels deep.
+    /// Every key gets one array per foreign key pointing at `table`, named
+    /// `<child table>_by_<column>`, and is looked up by its text like `ScopeObject::key`.
+    /// Views only show rows of their tables again, so they are left out.
+    pub async fn children(
+        &self,
+        table: &str,
+        keys: &[Value],
+        depth: usize,
+    ) -> anyhow::Result<HashMap<String, Map<String, Value>>> {
+        let mut out: HashMap<String, Map<String, Value>> =
+            keys.iter().map(|k| (key_text(k), Map::new())).collect();
+        if depth == 0 {
+            return Ok(out);
+        }
+        let parent = self
+            .node(table)
+            .ok_or_else(|| anyhow::anyhow!("Table '{}' not found in schema", table))?;
+
+        for (child, fk) in self
+            .edges()
+            .filter(|(child, fk)| fk.references.table == table && !self.is_view(&child.name))
+        {
+            let name = format!("{}_by_{}", child.name, fk.column);
+            let mut rows: Vec<(String, Map<String, Value>)> = Vec::new();
+
+            for chunk in keys.chunks(CHUNK_SIZE) {
+                let sql = format!(
+                    "SELECT CAST(p.{} AS TEXT), {} FROM {} c JOIN {} p ON p.{} = c.{} WHERE p.{} IN ({}) ORDER BY c.{}",
+                    parent.primary_key,
+                    child
+                        .columns
+                        .iter()
+                        .map(|col| format!("c.{}", col.name))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    child.name,
+                    parent.name,
+                    fk.references.column,
+                    fk.column,
+                    parent.primary_key,
+                    vec!["?"; chunk.len()].join(", "),
+                    child.primary_key
+                );
+                let mut query = sqlx::query(&sql);
+                for key in chunk {
+                    query = bind_value(query, key);
+                }
+                for row in query.fetch_all(&self.pool).await? {
+                    let object = child
+                        .columns
+                        .iter()
+                        .enumerate()
+                        .map(|(i, col)| (col.name.clone(), typed_value(&row, i + 1, &col.col_type)))
+                        .collect();
+                    rows.push((row.get(0), object));
+                }
+            }
+
+            // Children of the children, for every child row at once
+            let child_keys: Vec<Value> = rows
+                .iter()
+                .map(|(_, o)| o.get(&child.primary_key).cloned().unwrap_or_default())
+                .collect();
+            let mut grandchildren =
+                Box::pin(self.children(&child.name, &child_keys, depth - 1)).await?;
+
+            for object in out.values_mut() {
+                object.insert(name.clone(), Value::Array(Vec::new()));
+            }
+            for (parent_key, mut object) in rows {
+                let child_key = object.get(&child.primary_key).map(key_text);
+                if let Some(nested) = child_key.and_then(|k| grandchildren.remove(&k)) {
+                    object.extend(nested);
+                }
+                if let Some(Value::Array(list)) =
+                    out.get_mut(&parent_key).and_then(|o| o.get_mut(&name))
+                {
+                    list.push(Value::Object(object));
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Storage {
+    /// Foreign key of a table pointing back at the table itself, a row's parent in a hierarchy.
+    /// Only the first one is used when a table has several.
+    pub fn parent_key(&self, table: &str) -> Option<&ForeignKeyConfig> {
+        self.node(table)?
+            .foreign_keys
+            .iter()
+            .find(|fk| fk.references.table == table)
+    }
+
+    /// Ancestors of each of `keys` in a self-referencing table, from the top of the hierarchy
+    /// down to the parent. Keys are primary keys as text; a parent chain leading back to a row
+    /// already on it is cut there with a warning.
+    pub async fn ancestors(
+        &self,
+        table: &str,
+        keys: &[String],
+    ) -> anyhow::Result<HashMap<String, Vec<Map<String, Value>>>> {
+        let node = self
+            .node(table)
+            .ok_or_else(|| anyhow::anyhow!("Table '{}' not found in schema", table))?;
+        let fk = self
+            .parent_key(table)
+            .ok_or_else(|| anyhow::anyhow!("Table '{}' does not reference itself", table))?;
+        let (pk, parent, parent_of) = (&node.primary_key, &fk.column, &fk.references.column);
+        let marker = |alias: &str| format!("char(31) || CAST({alias}.{pk} AS TEXT) || char(31)");
+
+        let mut out: HashMap<String, Vec<Map<String, Value>>> = HashMap::new();
+        for chunk in keys.chunks(CHUNK_SIZE) {
+            let sql = format!(
+                "WITH RECURSIVE up(start, parent, depth, visited) AS (
+                   SELECT CAST(t.{pk} AS TEXT), t.{parent}, 1, {start} FROM {table} t
+                   WHERE t.{pk} IN ({keys}) AND t.{parent} IS NOT NULL
+                   UNION ALL
+                   SELECT up.start, p.{parent}, up.depth + 1, up.visited || {step} FROM up
+                   JOIN {table} p ON p.{parent_of} = up.parent
+                   WHERE p.{parent} IS NOT NULL AND instr(up.visited, {step}) = 0
+                 )
+                 SELECT up.start, instr(up.visited, {step}) > 0, {columns} FROM up
+                 JOIN {table} p ON p.{parent_of} = up.parent
+                 ORDER BY up.start, up.depth DESC",
+                start = marker("t"),
+                step = marker("p"),
+                keys = vec!["?"; chunk.len()].join(", "),
+                columns = node
+                    .columns
+                    .iter()
+                    .map(|c| format!("p.{}", c.name))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+            let mut query = sqlx::query(&sql);
+            for key in chunk {
+                query = query.bind(key.clone());
+            }
+            for row in query.fetch_all(&self.pool).await? {
+                let start: String = row.get(0);
+                if row.get::<bool, _>(1) {
+                    log::warn!(
+                        "`{}` {}: parents through `{}` lead back to a row already among them",
+                        table,
+                        start,
+                        parent
+                    );
+                    continue;
+                }
+                let ancestor = node
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| (c.name.clone(), typed_value(&row, i + 2, &c.col_type)))
+                    .collect();
+                out.entry(start).or_default().push(ancestor);
+            }
+        }
+        Ok(out)
+    }
+}
+
+pub fn bind_value<'q>(
+    query: Query<'q, Sqlite, SqliteArguments<'q>>,
+    value: &Value,
+) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    match value {
+        Value::String(s) => query.bind(s.clone()),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => query.bind(i),
+            None => query.bind(n.as_f64()),
+        },
+        Value::Bool(b) => query.bind(*b),
+        Value::Null => query.bind(None::<String>),
+        other => query.bind(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load::parse_tables::SchemaConfig;
+    use crate::testing::schema;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn collects_children_per_foreign_key() {
+        let mut schema = schema();
+        // A view re-exposing a foreign key adds no second array
+        schema.views.push(
+            serde_json::from_value(json!({
+                "name": "vv",
+                "sql": "SELECT name, fb FROM valves",
+                "key": "name",
+                "foreign_keys": [{ "column": "fb", "references": { "table": "io", "column": "id" } }]
+            }))
+            .unwrap(),
+        );
+        let data = serde_json::from_value(json!({
+            "racks": [{ "id": 1 }, { "id": 2 }],
+            "io": [{ "id": "B", "rack": 1 }, { "id": "A", "rack": 1 }],
+            "valves": [{ "name": "V1", "fb": "A" }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+        let keys = [json!(1), json!(2)];
+
+        let mut children = storage.children("racks", &keys, 2).await.unwrap();
+        assert_eq!(
+            Value::Object(children.remove("1").unwrap()),
+            json!({ "io_by_rack": [
+                { "id": "A", "rack": 1, "valves_by_fb": [{ "name": "V1", "fb": "A" }] },
+                { "id": "B", "rack": 1, "valves_by_fb": [] }
+            ]})
+        );
+        assert_eq!(
+            Value::Object(children.remove("2").unwrap()),
+            json!({ "io_by_rack": [] })
+        );
+
+        let shallow = storage.children("racks", &keys, 1).await.unwrap();
+        assert_eq!(
+            shallow["1"]["io_by_rack"][0],
+            json!({ "id": "A", "rack": 1 })
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_ancestors_from_the_top() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [{
+                "name": "areas",
+                "primary_key": "id",
+                "columns": [{ "name": "id", "type": "text" }, { "name": "parent_id", "type": "text" }],
+                "foreign_keys": [{ "column": "parent_id", "references": { "table": "areas", "column": "id" } }]
+            }]
+        }))
+        .unwrap();
+        let data = serde_json::from_value(json!({
+            "areas": [
+                { "id": "Plant" },
+                { "id": "Area1", "parent_id": "Plant" },
+                { "id": "Line2", "parent_id": "Area1" },
+                { "id": "Loop", "parent_id": "Loop" }
+            ]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+
+        let keys = ["Line2", "Plant", "Loop"].map(String::from);
+        let ancestors = storage.ancestors("areas", &keys).await.unwrap();
+        let ids: Vec<&Value> = ancestors["Line2"].iter().map(|a| &a["id"]).collect();
+        assert_eq!(ids, [&json!("Plant"), &json!("Area1")]);
+        assert!(!ancestors.contains_key("Plant"));
+        assert!(!ancestors.contains_key("Loop"));
+    }
+
+    #[tokio::test]
+    async fn reverse_closure_follows_several_hops() {
+        let data = serde_json::from_value(json!({
+            "racks": [{ "id": 1 }, { "id": 2 }],
+            "io": [{ "id": "A", "rack": 1 }, { "id": "B", "rack": 2 }],
+            "valves": [{ "name": "V1", "fb": "A" }, { "name": "V2", "fb": "B" }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema(), data).await.unwrap();
+
+        let start = vec![storage.row_key("racks:1").unwrap()];
+        let reached = storage.reverse_closure(start).await.unwrap();
+        let row = |t: &str, k: Value| (t.to_string(), k);
+        assert_eq!(
+            reached,
+            HashSet::from([
+                row("racks", json!(1)),
+                row("io", json!("A")),
+                row("valves", json!("V1"))
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_where_used_and_unreferenced_rows() {
+        let mut schema = schema();
+        // A view re-exposing a foreign key adds no references of its own
+        schema.views.push(
+            serde_json::from_value(json!({
+                "name": "all_valves",
+                "sql": "SELECT name, fb FROM valves",
+                "key": "name",
+                "foreign_keys": [{ "column": "fb", "references": { "table": "io", "column": "id" } }]
+            }))
+            .unwrap(),
+        );
+        let data = serde_json::from_value(json!({
+            "racks": [{ "id": 1 }, { "id": 2 }],
+            "io": [{ "id": "A", "rack": 1 }, { "id": "B", "rack": 1 }, { "id": "C", "rack": 1 }],
+            "valves": [{ "name": "V1", "fb": "A" }, { "name": "V2", "fb": "B" }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+
+        let refs = storage
+            .where_used(storage.row_key("racks:1").unwrap())
+            .await
+            .unwrap();
+        let refs: Vec<_> = refs
+            .iter()
+            .map(|r| (r.depth, r.table.as_str(), r.column.as_str(), r.key.clone()))
+            .collect();
+        assert_eq!(
+            refs,
+            [
+                (1, "io", "rack", json!("A")),
+                (2, "valves", "fb", json!("V1")),
+                (1, "io", "rack", json!("B")),
+                (2, "valves", "fb", json!("V2")),
+                (1, "io", "rack", json!("C"))
+            ]
+        );
+
+        let io = storage.node("io").unwrap();
+        assert_eq!(
+            storage.unreferenced_rows(io).await.unwrap(),
+            Some(vec![json!("C")])
+        );
+        let racks = storage.node("racks").unwrap();
+        assert_eq!(
+            storage.unreferenced_rows(racks).await.unwrap(),
+            Some(vec![json!(2)])
+        );
+        let valves = storage.node("valves").unwrap();
+        assert_eq!(storage.unreferenced_rows(valves).await.unwrap(), None);
+    }
+}
@@ -1,36 +1,148 @@
+use crate::data::fetch::source_tables;
 use crate::load::parse_data::DataFile;
-use crate::load::parse_tables::{SchemaConfig, TableConfig};
-use sqlx::sqlite::SqliteQueryResult;
-use sqlx::{Result, SqlitePool};
-use sqlx::{Row, sqlite::SqlitePoolOptions};
+use crate::load::parse_tables::{
+    ColumnConfig, ForeignKeyConfig, SchemaConfig, TableConfig, ViewConfig,
+};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{Result, Row, SqlitePool};
 
 pub struct Storage {
     pub schema: SchemaConfig,
     pub pool: SqlitePool,
+    /// Schema views described as tables, with the columns SQLite reports for them
+    pub views: Vec<TableConfig>,
 }
 
-const MEM: &str = "sqlite::memory:?cache=shared";
+/// Opened as a plain filename, `sqlite::memory:` would also keep `VACUUM INTO` targets in memory
+const MEM: &str = ":memory:";
+
+/// Single connection pool that never closes its connection, an in-memory database
+/// lives exactly as long as it
+pub(super) async fn connect(options: SqliteConnectOptions) -> Result<SqlitePool> {
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(options.foreign_keys(true))
+        .await
+}
 
 impl Storage {
     pub async fn new(schema: SchemaConfig, data: DataFile) -> Result<Self> {
         log::info!("Loaded file, creating SQLite database");
-        let pool = SqlitePoolOptions::new()
-            .max_connections(1)
-            .connect(MEM)
-            .await?;
+        let pool = connect(SqliteConnectOptions::new().filename(MEM)).await?;
 
-        let s = Self {
+        let mut s = Self {
             schema: schema.clone(),
             pool,
+            views: Vec::new(),
         };
         for table in schema.tables {
             s.create_table(&table).await?;
         }
 
         s.init_data(&data).await?;
+
+        for view in schema.views {
+            let described = s.create_view(&view, "").await?;
+            s.views.push(described);
+        }
         Ok(s)
     }
 
+    /// Tables followed by views, everything a script can use as a root
+    pub fn nodes(&self) -> impl Iterator<Item = &TableConfig> {
+        self.schema.tables.iter().chain(self.views.iter())
+    }
+
+    /// Every foreign key of the schema with the table or view declaring it
+    pub fn edges(&self) -> impl Iterator<Item = (&TableConfig, &ForeignKeyConfig)> {
+        self.nodes()
+            .flat_map(|t| t.foreign_keys.iter().map(move |fk| (t, fk)))
+    }
+
+    pub fn is_view(&self, name: &str) -> bool {
+        self.views.iter().any(|v| v.name == name)
+    }
+
+    pub fn node(&self, name: &str) -> Option<&TableConfig> {
+        self.nodes().find(|t| t.name == name)
+    }
+
+    pub fn column(&self, table: &str, column: &str) -> Option<&ColumnConfig> {
+        self.node(table)?.columns.iter().find(|c| c.name == column)
+    }
+
+    /// Creates a view, `kind` is empty or `"TEMP "` for a view outside the database file
+    pub(super) async fn create_view(
+        &self,
+        view: &ViewConfig,
+        kind: &str,
+    ) -> Result<TableConfig, sqlx::Error> {
+        sqlx::query(&format!("CREATE {kind}VIEW {} AS {}", view.name, view.sql))
+            .execute(&self.pool)
+            .await?;
+
+        log::info!("Successfuly created view: {}", &view.name);
+        self.describe_view(view).await
+    }
+
+    /// Reads the columns of an existing view and checks the declared key and foreign keys.
+    /// A column named like one of a table the view reads from, with the same SQLite type,
+    /// keeps that column's schema type, so a `bool` stays one.
+    pub(super) async fn describe_view(
+        &self,
+        view: &ViewConfig,
+    ) -> Result<TableConfig, sqlx::Error> {
+        let sources: Vec<&TableConfig> = source_tables(&view.sql)
+            .iter()
+            .filter_map(|t| self.node(t))
+            .collect();
+        let columns: Vec<ColumnConfig> = sqlx::query(&format!("PRAGMA table_info({})", view.name))
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| {
+                let name: String = row.get("name");
+                let declared: String = row.get("type");
+                let col_type = sources
+                    .iter()
+                    .filter_map(|t| t.columns.iter().find(|c| c.name == name))
+                    .map(|c| c.col_type.clone())
+                    .find(|t| sql_type(t) == declared)
+                    .unwrap_or_else(|| schema_type(declared));
+                ColumnConfig {
+                    name,
+                    col_type,
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        let missing = std::iter::once(&view.key)
+            .chain(view.foreign_keys.iter().map(|fk| &fk.column))
+            .find(|c| !columns.iter().any(|col| &col.name == *c));
+        if let Some(col) = missing {
+            return Err(sqlx::Error::Configuration(
+                format!(
+                    "View {}: column {} not returned by its query",
+                    view.name, col
+                )
+                .into(),
+            ));
+        }
+
+        Ok(TableConfig {
+            name: view.name.clone(),
+            primary_key: view.key.clone(),
+            columns,
+            foreign_keys: view.foreign_keys.clone(),
+            description: view.description.clone(),
+            label: view.label.clone(),
+            ..Default::default()
+        })
+    }
+
     async fn create_table(&self, table: &TableConfig) -> Result<(), sqlx::Error> {
         let mut qb = sqlx::QueryBuilder::new("CREATE TABLE ");
 
@@ -40,20 +152,30 @@ impl Storage {
         let mut separated = qb.separated(", ");
 
         for col in &table.columns {
-            separated.push(format!("{} {}", col.name, col.col_type));
+            let sql_type = sql_type(&col.col_type);
+            match &col.expr {
+                Some(expr) => separated.push(format!(
+                    "{} {} GENERATED ALWAYS AS ({}) VIRTUAL",
+                    col.name, sql_type, expr
+                )),
+                None if col.col_type == "bool" => {
+                    separated.push(format!("{0} {1} CHECK ({0} IN (0, 1))", col.name, sql_type))
+                }
+                None => separated.push(format!("{} {}", col.name, sql_type)),
+            };
         }
 
         separated.push(format!("PRIMARY KEY ({})", table.primary_key));
 
+        // Tables are filled in arbitrary order, references are checked on commit
         for fk in &table.foreign_keys {
             separated.push(format!(
-                "FOREIGN KEY ({}) REFERENCES {}({})",
+                "FOREIGN KEY ({}) REFERENCES {}({}) DEFERRABLE INITIALLY DEFERRED",
                 fk.column, fk.references.table, fk.references.column
             ));
         }
 
-        drop(separated);
-        qb.push(")");
+        qb.push(") STRICT");
 
         let query = qb.build();
         query.execute(&self.pool).await?;
@@ -88,7 +210,9 @@ impl Storage {
 }
 
 impl Storage {
-    async fn execute_batch<'a>(
+    /// Inserts rows of one table, taking the column list from the schema rather than the
+    /// rows, so rows with different sets of columns end up in the same INSERT
+    async fn execute_batch(
         &self,
         tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
         table_name: &str,
@@ -98,21 +222,35 @@ impl Storage {
             return Ok(());
         }
 
-        let columns: Vec<&String> = batch[0].keys().collect();
+        // Every stored column of the table, rows without a value get NULL
+        let table = self
+            .schema
+            .tables
+            .iter()
+            .find(|t| t.name == table_name)
+            .ok_or_else(|| {
+                sqlx::Error::Configuration(format!("no table `{table_name}` in schema").into())
+            })?;
+        let columns: Vec<&ColumnConfig> =
+            table.columns.iter().filter(|c| !c.is_computed()).collect();
 
         let mut qb = sqlx::QueryBuilder::new(format!(
             "INSERT INTO {} ({})",
             table_name,
             columns
                 .iter()
-                .map(|c| c.as_str().to_string())
+                .map(|c| c.name.as_str())
                 .collect::<Vec<_>>()
                 .join(", ")
         ));
 
         qb.push_values(batch.iter(), |mut row_builder, row| {
             for col in &columns {
-                bind_json_value(&mut row_builder, row.get(*col).unwrap());
+                bind_json_value(
+                    &mut row_builder,
+                    row.get(&col.name).unwrap_or(&serde_json::Value::Null),
+                    &col.col_type,
+                );
             }
         });
 
@@ -121,16 +259,40 @@ impl Storage {
     }
 }
 
+/// SQLite type of a schema column type in a STRICT table, booleans are stored as 0 and 1
+/// and anything that is not a number is TEXT
+fn sql_type(col_type: &str) -> &'static str {
+    match col_type {
+        "int" | "bool" => "INTEGER",
+        "float" => "REAL",
+        _ => "TEXT",
+    }
+}
+
+/// Schema column type of a declared SQLite type, for view columns
+fn schema_type(sql_type: String) -> String {
+    match sql_type.as_str() {
+        "INTEGER" => "int".to_string(),
+        "REAL" => "float".to_string(),
+        "TEXT" => "text".to_string(),
+        _ => sql_type,
+    }
+}
+
+/// Binds a value validated against `col_type`, floats are always bound as REAL
 fn bind_json_value<'q>(
     row_builder: &mut sqlx::query_builder::Separated<'q, '_, sqlx::Sqlite, &'static str>,
     value: &serde_json::Value,
+    col_type: &str,
 ) {
     match value {
         serde_json::Value::String(s) => {
             row_builder.push_bind(s.clone());
         }
         serde_json::Value::Number(n) => {
-            if let Some(i) = n.as_i64() {
+            if col_type == "float" {
+                row_builder.push_bind(n.as_f64());
+            } else if let Some(i) = n.as_i64() {
                 row_builder.push_bind(i);
             } else if let Some(f) = n.as_f64() {
                 row_builder.push_bind(f);
@@ -139,7 +301,7 @@ fn bind_json_value<'q>(
             }
         }
         serde_json::Value::Bool(b) => {
-            row_builder.push_bind(*b);
+            row_builder.push_bind(*b as i64);
         }
         serde_json::Value::Null => {
             row_builder.push_bind(None::<String>);
@@ -149,3 +311,96 @@ fn bind_json_value<'q>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn inserts_rows_with_different_columns() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [{
+                "name": "io",
+                "primary_key": "id",
+                "columns": [
+                    { "name": "id", "type": "text" },
+                    { "name": "rack", "type": "int" },
+                    { "name": "slot", "type": "int" }
+                ]
+            }]
+        }))
+        .unwrap();
+        let data = serde_json::from_value(json!({
+            "io": [{ "id": "A", "rack": 1 }, { "id": "B" }, { "id": "C", "rack": 2, "slot": 3 }]
+        }))
+        .unwrap();
+
+        let storage = Storage::new(schema, data).await.unwrap();
+        let rows = sqlx::query("SELECT id, rack, slot FROM io ORDER BY id")
+            .fetch_all(&storage.pool)
+            .await
+            .unwrap();
+        let values: Vec<(String, Option<i64>, Option<i64>)> = rows
+            .iter()
+            .map(|r| (r.get(0), r.get(1), r.get(2)))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("A".to_string(), Some(1), None),
+                ("B".to_string(), None, None),
+                ("C".to_string(), Some(2), Some(3))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn enforces_types_and_references() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [
+                {
+                    "name": "io",
+                    "primary_key": "id",
+                    "columns": [
+                        { "name": "id", "type": "text" },
+                        { "name": "scale", "type": "float" },
+                        { "name": "spare", "type": "bool" }
+                    ]
+                },
+                {
+                    "name": "valves",
+                    "primary_key": "name",
+                    "columns": [{ "name": "name", "type": "text" }, { "name": "fb", "type": "text" }],
+                    "foreign_keys": [{ "column": "fb", "references": { "table": "io", "column": "id" } }]
+                }
+            ]
+        }))
+        .unwrap();
+        let data = serde_json::from_value(json!({
+            "valves": [{ "name": "V1", "fb": "A" }],
+            "io": [{ "id": "A", "scale": 2, "spare": true }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+
+        let row = sqlx::query("SELECT typeof(scale), typeof(spare), spare FROM io")
+            .fetch_one(&storage.pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>(0), "real");
+        assert_eq!(row.get::<String, _>(1), "integer");
+        assert_eq!(row.get::<i64, _>(2), 1);
+
+        for bad in [
+            "INSERT INTO io (id, scale) VALUES ('B', 'high')",
+            "INSERT INTO io (id, spare) VALUES ('B', 2)",
+            "INSERT INTO valves (name, fb) VALUES ('V2', 'missing')",
+        ] {
+            assert!(
+                sqlx::query(bad).execute(&storage.pool).await.is_err(),
+                "{bad}"
+            );
+        }
+    }
+}
@@ -1,10 +1,21 @@
 use crate::load::{parse_data::DataFile, parse_tables::SchemaConfig};
 use anyhow::Result;
+pub use export::{ExportFormat, write_export};
+pub use fetch::{FetchRoot, ROOT_KEY, root_table_name};
+pub use graph::RowKey;
 pub use init::Storage;
 use once_cell::sync::OnceCell;
+pub use query::column_value;
+pub use scope::{ScopeNode, ScopeObject, ScopeOptions, key_text};
+use std::path::Path;
 
+mod export;
+mod fetch;
+mod graph;
 mod init;
+mod persist;
 mod query;
+mod scope;
 
 static STORAGE: OnceCell<Storage> = OnceCell::new();
 
@@ -15,6 +26,14 @@ pub async fn init(schema: SchemaConfig, data: DataFile) -> Result<()> {
         .map_err(|_| anyhow::anyhow!("Storage already initialized"))?;
     Ok(())
 }
+/// Uses an SQLite file saved earlier instead of loading the data files
+pub async fn open(schema: SchemaConfig, path: &Path) -> Result<()> {
+    let storage = Storage::open(schema, path).await?;
+    STORAGE
+        .set(storage)
+        .map_err(|_| anyhow::anyhow!("Storage already initialized"))?;
+    Ok(())
+}
 pub fn get_storage() -> &'static Storage {
     STORAGE.get().expect("Storage not initialized")
 }
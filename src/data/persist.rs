@@ -0,0 +1,210 @@
+use anyhow::{Result, anyhow};
+use sqlx::Row;
+use sqlx::sqlite::SqliteConnectOptions;
+use std::path::Path;
+
+use crate::data::init::{Storage, connect};
+use crate::load::parse_data::validate;
+use crate::load::parse_tables::{SchemaConfig, TableConfig};
+
+impl Storage {
+    /// Opens an existing SQLite file read-only and checks it holds data valid for `schema`,
+    /// with the same checks as loading `data.json`. Views are created as temporary views
+    /// from the schema, so the file is never written to.
+    pub async fn open(schema: SchemaConfig, path: &Path) -> Result<Self> {
+        log::info!("Opening SQLite database {:?}", path);
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .read_only(true)
+            .create_if_missing(false);
+        let pool = connect(options)
+            .await
+            .map_err(|e| anyhow!("{:?}: {e}", path))?;
+
+        let mut s = Self {
+            schema: schema.clone(),
+            pool,
+            views: Vec::new(),
+        };
+        for table in &schema.tables {
+            s.verify_table(table).await?;
+        }
+
+        let violations = sqlx::query("PRAGMA foreign_key_check")
+            .fetch_all(&s.pool)
+            .await?;
+        if let Some(v) = violations.first() {
+            return Err(anyhow!(
+                "FK violation: `{}` row {} references missing row in `{}` ({} violations)",
+                v.get::<String, _>("table"),
+                v.get::<i64, _>("rowid"),
+                v.get::<String, _>("parent"),
+                violations.len()
+            ));
+        }
+
+        // Enum values, patterns and keys, read back the way they would be exported
+        validate(&s.data().await?, &schema, &()).map_err(|e| anyhow!("{:?}: {e}", path))?;
+
+        // Temporary views take precedence over views saved in the file
+        for view in &schema.views {
+            let described = s.create_view(view, "TEMP ").await?;
+            s.views.push(described);
+        }
+
+        log::info!("Verified {} tables against the schema", schema.tables.len());
+        Ok(s)
+    }
+
+    /// Writes the whole database to a SQLite file, replacing an existing one.
+    /// The file is written next to the target first, a failed save leaves the old file intact.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{:?}: not a file name", path))?;
+        let temp = path.with_file_name(format!(
+            ".{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        if temp.exists() {
+            std::fs::remove_file(&temp)?;
+        }
+
+        let written = sqlx::query("VACUUM INTO ?")
+            .bind(temp.to_string_lossy().to_string())
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&temp);
+            return Err(anyhow!("{:?}: {e}", path));
+        }
+        std::fs::rename(&temp, path).map_err(|e| anyhow!("{:?}: {e}", path))?;
+        log::info!("Saved database to {:?}", path);
+        Ok(())
+    }
+
+    async fn verify_table(&self, table: &TableConfig) -> Result<()> {
+        let info = sqlx::query(&format!("PRAGMA table_xinfo({})", table.name))
+            .fetch_all(&self.pool)
+            .await?;
+        if info.is_empty() {
+            return Err(anyhow!("Table `{}` not found in database", table.name));
+        }
+
+        for col in &table.columns {
+            let found = info
+                .iter()
+                .find(|r| r.get::<String, _>("name") == col.name)
+                .ok_or_else(|| {
+                    anyhow!("Table `{}`: column `{}` not found", table.name, col.name)
+                })?;
+
+            if col.name == table.primary_key && found.get::<i64, _>("pk") == 0 {
+                return Err(anyhow!(
+                    "Table `{}`: column `{}` is not the primary key",
+                    table.name,
+                    col.name
+                ));
+            }
+            if col.is_computed() {
+                continue;
+            }
+
+            let c = &col.name;
+            let allowed = match col.col_type.as_str() {
+                "int" => format!("typeof({c}) = 'integer'"),
+                "float" => format!("typeof({c}) IN ('real', 'integer')"),
+                "bool" => format!("{c} IN (0, 1)"),
+                _ => format!("typeof({c}) = 'text'"),
+            };
+            let sql = format!(
+                "SELECT COUNT(*) FROM {} WHERE {} IS NOT NULL AND NOT ({})",
+                table.name, col.name, allowed
+            );
+            let bad: i64 = sqlx::query(&sql).fetch_one(&self.pool).await?.get(0);
+            if bad > 0 {
+                return Err(anyhow!(
+                    "Table `{}`: {} rows with column `{}` not of type {}",
+                    table.name,
+                    bad,
+                    col.name,
+                    col.col_type
+                ));
+            }
+        }
+
+        for row in &info {
+            let name: String = row.get("name");
+            if !table.columns.iter().any(|c| c.name == name) {
+                log::warn!("Table `{}`: column `{}` not in schema", table.name, name);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> SchemaConfig {
+        serde_json::from_value(json!({
+            "tables": [
+                {
+                    "name": "io",
+                    "primary_key": "id",
+                    "columns": [{ "name": "id", "type": "text" }, { "name": "rack", "type": "int" }]
+                },
+                {
+                    "name": "valves",
+                    "primary_key": "name",
+                    "columns": [{ "name": "name", "type": "text" }, { "name": "fb", "type": "text" }],
+                    "foreign_keys": [{ "column": "fb", "references": { "table": "io", "column": "id" } }]
+                }
+            ],
+            "views": [{ "name": "rack1", "sql": "SELECT * FROM io WHERE rack = 1", "key": "id" }]
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn saves_and_reopens_database() {
+        let data = serde_json::from_value(json!({
+            "io": [{ "id": "A", "rack": 1 }, { "id": "B", "rack": 2 }],
+            "valves": [{ "name": "V1", "fb": "A" }]
+        }))
+        .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("traverse_save_{}.sqlite", std::process::id()));
+
+        let storage = Storage::new(schema(), data).await.unwrap();
+        storage.save(&path).await.unwrap();
+        // Saving again replaces the file
+        storage.save(&path).await.unwrap();
+        let saved = std::fs::read(&path).unwrap();
+
+        let opened = Storage::open(schema(), &path).await.unwrap();
+        let rows = opened
+            .query(&"SELECT id FROM rack1".to_string())
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(opened.views.len(), 1);
+
+        let mut other = schema();
+        other.tables[0].columns[1].col_type = "text".to_string();
+        let err = Storage::open(other, &path).await.map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("not of type text"), "{err}");
+
+        let mut other = schema();
+        other.tables[0].columns[1].allowed = Some(vec![json!(1)]);
+        let err = Storage::open(other, &path).await.map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("is not one of [1]"), "{err}");
+
+        drop(opened);
+        assert_eq!(std::fs::read(&path).unwrap(), saved);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
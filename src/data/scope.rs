@@ -0,0 +1,580 @@
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sqlx::{Column, Row};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::data::fetch::{FetchRoot, ROOT_KEY};
+use crate::data::graph::{CHUNK_SIZE, bind_value};
+use crate::data::init::Storage;
+use crate::data::query::{column_value, typed_value};
+use crate::load::parse_tables::{ForeignKeyConfig, TableConfig};
+
+/// Limits on the foreign keys scope mode follows from the root.
+/// Paths are the foreign key columns followed, joined by `.`, as in `open_feedback.rack`.
+#[derive(Debug, Deserialize)]
+pub struct ScopeOptions {
+    /// Most foreign keys followed in a row from the root
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+    /// Paths to follow, along with everything below them; empty follows every path
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Paths never followed, along with everything below them
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// The hops the former hardcoded `depth < 10` allowed, the root being depth 1
+fn default_max_depth() -> usize {
+    9
+}
+
+impl Default for ScopeOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: default_max_depth(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl ScopeOptions {
+    /// Whether the foreign key path `path` is followed
+    fn follows(&self, path: &str) -> bool {
+        let within = |p: &str, of: &str| p == of || p.starts_with(&format!("{of}."));
+        // On the way to an included path, at it, or below it
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|i| within(path, i) || within(i, path));
+        included && !self.exclude.iter().any(|e| within(path, e))
+    }
+}
+
+/// A row with every column of its table or view, typed after the schema
+pub type ScopeRow = Arc<Map<String, Value>>;
+
+/// A row reached from a scope root through the foreign key columns of `prefix`
+#[derive(Debug)]
+pub struct ScopeNode {
+    /// Foreign key columns followed from the root joined by `.`, empty for the root itself
+    pub prefix: String,
+    pub table: String,
+    pub row: ScopeRow,
+}
+
+/// A row reached again on the path leading to it, not expanded a second time
+#[derive(Debug)]
+pub struct ScopeCycle {
+    pub prefix: String,
+    pub table: String,
+    pub key: Value,
+}
+
+/// Everything scope mode collects for one row of the fetch statement
+#[derive(Debug)]
+pub struct ScopeObject {
+    /// Primary key of the root as text
+    pub key: String,
+    /// Every column the fetch statement returned for the root
+    pub fetched: Map<String, Value>,
+    /// The root first, then the rows it reaches, nearest first
+    pub nodes: Vec<ScopeNode>,
+    pub cycles: Vec<ScopeCycle>,
+}
+
+/// Rows read so far, per table and column, by the text of that column
+type RowCache<'a> = HashMap<(&'a str, &'a str), HashMap<String, ScopeRow>>;
+
+/// Where a node sits in its object, the rows on its path are found through `parent`
+struct Link {
+    parent: Option<usize>,
+    key: String,
+}
+
+impl Storage {
+    /// Runs the fetch statement and follows foreign keys from each of its rows.
+    /// Rows are read level by level, one batch per referenced table, and every row
+    /// shared by several objects is read only once.
+    pub async fn resolve_scope(
+        &self,
+        fetch: &str,
+        options: &ScopeOptions,
+    ) -> Result<Vec<ScopeObject>> {
+        let root = FetchRoot::parse(fetch)?;
+        let root_table = self
+            .node(&root.table)
+            .ok_or_else(|| anyhow!("Root table '{}' not found in schema", root.table))?;
+
+        // Foreign keys by the table declaring them, self references are exposed as ancestors
+        let mut outgoing: HashMap<&str, Vec<&ForeignKeyConfig>> = HashMap::new();
+        let mut parents = Vec::new();
+        for (table, fk) in self.edges() {
+            match table.name == fk.references.table {
+                true => parents.push((table.name.as_str(), fk)),
+                false => outgoing.entry(&table.name).or_default().push(fk),
+            }
+        }
+
+        let reachable = reachable(&outgoing, &root_table.name);
+        for (table, fk) in parents {
+            if reachable.contains(table) {
+                log::warn!(
+                    "Table `{}` references itself through `{}`, scope mode lists its ancestors instead of following it",
+                    table,
+                    fk.column
+                );
+            }
+        }
+
+        let fetched = self.fetch_columns(&root, root_table).await?;
+        let mut cache = RowCache::new();
+        let keys = fetched.iter().map(|(_, k, _)| k.clone()).collect();
+        self.read_rows(&mut cache, root_table, &root_table.primary_key, keys)
+            .await?;
+
+        let mut objects = Vec::new();
+        let mut links = Vec::new();
+        let roots = &cache[&(root_table.name.as_str(), root_table.primary_key.as_str())];
+        for (key, _, fetched) in fetched {
+            let Some(row) = roots.get(&key) else {
+                continue;
+            };
+            links.push(vec![Link {
+                parent: None,
+                key: key.clone(),
+            }]);
+            objects.push(ScopeObject {
+                key,
+                fetched,
+                nodes: vec![ScopeNode {
+                    prefix: String::new(),
+                    table: root_table.name.clone(),
+                    row: row.clone(),
+                }],
+                cycles: Vec::new(),
+            });
+        }
+        // Nodes of the last level as object and node index
+        let mut frontier: Vec<(usize, usize)> = (0..objects.len()).map(|o| (o, 0)).collect();
+
+        for _ in 0..options.max_depth {
+            // Foreign keys to follow from the current level and the keys they need
+            let mut steps = Vec::new();
+            let mut wanted: HashMap<(&str, &str), HashMap<String, Value>> = HashMap::new();
+            for &(object, node) in &frontier {
+                let node_at = &objects[object].nodes[node];
+                for fk in outgoing.get(node_at.table.as_str()).into_iter().flatten() {
+                    let Some(value) = node_at.row.get(&fk.column).filter(|v| !v.is_null()) else {
+                        continue;
+                    };
+                    let prefix = match node_at.prefix.is_empty() {
+                        true => fk.column.clone(),
+                        false => format!("{}.{}", node_at.prefix, fk.column),
+                    };
+                    if !options.follows(&prefix) {
+                        continue;
+                    }
+                    let target = (fk.references.table.as_str(), fk.references.column.as_str());
+                    let key = key_text(value);
+                    if !cache
+                        .get(&target)
+                        .is_some_and(|rows| rows.contains_key(&key))
+                    {
+                        wanted
+                            .entry(target)
+                            .or_default()
+                            .insert(key.clone(), value.clone());
+                    }
+                    steps.push((object, node, prefix, target, key));
+                }
+            }
+            if steps.is_empty() {
+                break;
+            }
+
+            for ((table, column), keys) in wanted {
+                let node = self
+                    .node(table)
+                    .ok_or_else(|| anyhow!("Table '{}' not found in schema", table))?;
+                self.read_rows(&mut cache, node, column, keys.into_values().collect())
+                    .await?;
+            }
+
+            let mut next = Vec::new();
+            for (object, parent, prefix, target, key) in steps {
+                let Some(row) = cache.get(&target).and_then(|rows| rows.get(&key)) else {
+                    continue;
+                };
+                let table = target.0;
+                let pk = &self.node(table).expect("read above").primary_key;
+                let row_key = row.get(pk).map(key_text).unwrap_or_default();
+                let (nodes, links) = (&mut objects[object].nodes, &mut links[object]);
+
+                let mut on_path = Some(parent);
+                while let Some(at) = on_path {
+                    if nodes[at].table == table && links[at].key == row_key {
+                        break;
+                    }
+                    on_path = links[at].parent;
+                }
+                if on_path.is_some() {
+                    objects[object].cycles.push(ScopeCycle {
+                        prefix,
+                        table: table.to_string(),
+                        key: row.get(pk).cloned().unwrap_or_default(),
+                    });
+                    continue;
+                }
+                next.push((object, nodes.len()));
+                links.push(Link {
+                    parent: Some(parent),
+                    key: row_key,
+                });
+                nodes.push(ScopeNode {
+                    prefix,
+                    table: table.to_string(),
+                    row: row.clone(),
+                });
+            }
+            frontier = next;
+        }
+
+        Ok(objects)
+    }
+
+    /// Every column the fetch statement returns, per root key as text and as read, in fetch
+    /// order. Columns of the root table are typed after the schema.
+    async fn fetch_columns(
+        &self,
+        root: &FetchRoot,
+        root_table: &TableConfig,
+    ) -> Result<Vec<(String, Value, Map<String, Value>)>> {
+        let sql = format!(
+            "SELECT f.*, CAST(f.{ROOT_KEY} AS TEXT) FROM ({}) f",
+            root.select_key(&root_table.primary_key)
+        );
+
+        let mut seen = HashSet::new();
+        let mut out = Vec::new();
+        for row in sqlx::query(&sql).fetch_all(&self.pool).await? {
+            let columns = row.columns();
+            // The appended key and its text form come last
+            let key_index = columns.len() - 1;
+            let key: String = row.try_get(key_index)?;
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let mut values = Map::new();
+            for column in &columns[..key_index - 1] {
+                let value = match self.column(&root.table, column.name()) {
+                    Some(c) => typed_value(&row, column.ordinal(), &c.col_type),
+                    None => column_value(&row, column.ordinal()),
+                };
+                values.insert(column.name().to_string(), value);
+            }
+            out.push((key, column_value(&row, key_index - 1), values));
+        }
+        Ok(out)
+    }
+
+    /// Reads the rows of `table` whose `column` is one of `keys` into the cache.
+    /// Keys are bound with their own type, a view column without a declared type
+    /// compares them as they are.
+    async fn read_rows<'a>(
+        &self,
+        cache: &mut RowCache<'a>,
+        table: &'a TableConfig,
+        column: &'a str,
+        keys: Vec<Value>,
+    ) -> Result<()> {
+        let rows = cache.entry((table.name.as_str(), column)).or_default();
+        let columns = table
+            .columns
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        for chunk in keys.chunks(CHUNK_SIZE) {
+            let sql = format!(
+                "SELECT {}, CAST({} AS TEXT) FROM {} WHERE {} IN ({})",
+                columns,
+                column,
+                table.name,
+                column,
+                vec!["?"; chunk.len()].join(", ")
+            );
+            let mut query = sqlx::query(&sql);
+            for key in chunk {
+                query = bind_value(query, key);
+            }
+            for row in query.fetch_all(&self.pool).await? {
+                let values: Map<String, Value> = table
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| (c.name.clone(), typed_value(&row, i, &c.col_type)))
+                    .collect();
+                rows.insert(row.try_get(table.columns.len())?, Arc::new(values));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tables and views reached from `root` through `outgoing`
+fn reachable<'a>(
+    outgoing: &HashMap<&'a str, Vec<&'a ForeignKeyConfig>>,
+    root: &'a str,
+) -> HashSet<&'a str> {
+    let mut seen = HashSet::from([root]);
+    let mut stack = vec![root];
+    while let Some(table) = stack.pop() {
+        for fk in outgoing.get(table).into_iter().flatten() {
+            if seen.insert(&fk.references.table) {
+                stack.push(&fk.references.table);
+            }
+        }
+    }
+    seen
+}
+
+/// Primary key as text, the way SQLite casts it
+pub fn key_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load::parse_tables::SchemaConfig;
+    use serde_json::json;
+
+    fn prefixes(object: &ScopeObject) -> Vec<&str> {
+        let mut prefixes: Vec<_> = object.nodes.iter().map(|n| n.prefix.as_str()).collect();
+        prefixes.sort();
+        prefixes
+    }
+
+    #[tokio::test]
+    async fn resolves_typed_rows_and_fetched_columns() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [
+                {
+                    "name": "io",
+                    "primary_key": "id",
+                    "columns": [
+                        { "name": "id", "type": "int" },
+                        { "name": "scale", "type": "float" },
+                        { "name": "spare", "type": "bool" }
+                    ]
+                },
+                {
+                    "name": "valves",
+                    "primary_key": "name",
+                    "columns": [{ "name": "name", "type": "text" }, { "name": "fb", "type": "int" }],
+                    "foreign_keys": [{ "column": "fb", "references": { "table": "io", "column": "id" } }]
+                }
+            ]
+        }))
+        .unwrap();
+        let data = serde_json::from_value(json!({
+            "io": [{ "id": 7, "scale": 0.5, "spare": true }],
+            "valves": [{ "name": "V1", "fb": 7 }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+        let options = ScopeOptions::default();
+
+        let objects = storage
+            .resolve_scope("SELECT * FROM valves", &options)
+            .await
+            .unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, "V1");
+        assert_eq!(prefixes(&objects[0]), ["", "fb"]);
+        assert_eq!(
+            Value::Object((*objects[0].nodes[1].row).clone()),
+            json!({ "id": 7, "scale": 0.5, "spare": true })
+        );
+
+        let objects = storage
+            .resolve_scope(
+                "SELECT v.*, io.scale * 2 AS doubled FROM valves v JOIN io ON io.id = v.fb",
+                &options,
+            )
+            .await
+            .unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(
+            Value::Object(objects[0].fetched.clone()),
+            json!({ "name": "V1", "fb": 7, "doubled": 1.0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_view_keys_without_declared_type() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [{ "name": "io", "primary_key": "id", "columns": [{ "name": "id", "type": "int" }] }],
+            "views": [
+                { "name": "doubled", "sql": "SELECT id * 2 AS num FROM io", "key": "num" },
+                {
+                    "name": "halves",
+                    "sql": "SELECT id * 2 AS twice, id FROM io",
+                    "key": "twice",
+                    "foreign_keys": [{ "column": "twice", "references": { "table": "doubled", "column": "num" } }]
+                }
+            ]
+        }))
+        .unwrap();
+        let data = serde_json::from_value(json!({ "io": [{ "id": 3 }] })).unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+
+        let objects = storage
+            .resolve_scope("SELECT * FROM halves", &ScopeOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, "6");
+        assert_eq!(prefixes(&objects[0]), ["", "twice"]);
+        assert_eq!(objects[0].nodes[1].row["num"], json!(6));
+    }
+
+    #[tokio::test]
+    async fn reads_view_columns_with_their_table_types() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [{
+                "name": "io",
+                "primary_key": "id",
+                "columns": [{ "name": "id", "type": "text" }, { "name": "spare", "type": "bool" }]
+            }],
+            "views": [{
+                "name": "spare_io",
+                "sql": "SELECT id, spare, spare + 0 AS flag FROM io WHERE spare",
+                "key": "id"
+            }]
+        }))
+        .unwrap();
+        let data =
+            serde_json::from_value(json!({ "io": [{ "id": "A", "spare": true }, { "id": "B" }] }))
+                .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+        assert_eq!(
+            storage.column("spare_io", "spare").unwrap().col_type,
+            "bool"
+        );
+
+        let objects = storage
+            .resolve_scope("SELECT * FROM spare_io", &ScopeOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            Value::Object((*objects[0].nodes[0].row).clone()),
+            json!({ "id": "A", "spare": true, "flag": 1 })
+        );
+    }
+
+    #[tokio::test]
+    async fn scope_options_limit_paths() {
+        // Valves with a second foreign key into io next to `fb`
+        let mut schema = crate::testing::schema();
+        let valves = &mut schema.tables[2];
+        valves
+            .columns
+            .push(serde_json::from_value(json!({ "name": "closed", "type": "text" })).unwrap());
+        valves.foreign_keys.push(
+            serde_json::from_value(
+                json!({ "column": "closed", "references": { "table": "io", "column": "id" } }),
+            )
+            .unwrap(),
+        );
+        let data = serde_json::from_value(json!({
+            "racks": [{ "id": 1 }],
+            "io": [{ "id": "A", "rack": 1 }, { "id": "B", "rack": 1 }],
+            "valves": [{ "name": "V1", "fb": "A", "closed": "B" }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+
+        let paths = async |options: ScopeOptions| {
+            let objects = storage
+                .resolve_scope("SELECT * FROM valves", &options)
+                .await
+                .unwrap();
+            prefixes(&objects[0])
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            paths(ScopeOptions::default()).await,
+            ["", "closed", "closed.rack", "fb", "fb.rack"]
+        );
+        let include = ScopeOptions {
+            include: vec!["fb".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(paths(include).await, ["", "fb", "fb.rack"]);
+        let exclude = ScopeOptions {
+            exclude: vec!["fb.rack".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(paths(exclude).await, ["", "closed", "closed.rack", "fb"]);
+        // Same reach as the former hardcoded limit
+        let defaults: ScopeOptions = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(defaults.max_depth, 9);
+        let shallow = ScopeOptions {
+            max_depth: 1,
+            ..Default::default()
+        };
+        assert_eq!(paths(shallow).await, ["", "closed", "fb"]);
+    }
+
+    #[tokio::test]
+    async fn detects_cycles_by_table_and_key() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [
+                {
+                    "name": "valves",
+                    "primary_key": "id",
+                    "columns": [{ "name": "id", "type": "int" }, { "name": "controller", "type": "int" }],
+                    "foreign_keys": [{ "column": "controller", "references": { "table": "controllers", "column": "id" } }]
+                },
+                {
+                    "name": "controllers",
+                    "primary_key": "id",
+                    "columns": [{ "name": "id", "type": "int" }, { "name": "valve", "type": "int" }],
+                    "foreign_keys": [{ "column": "valve", "references": { "table": "valves", "column": "id" } }]
+                }
+            ]
+        }))
+        .unwrap();
+        let data = serde_json::from_value(json!({
+            "valves": [{ "id": 5, "controller": 5 }],
+            "controllers": [{ "id": 5, "valve": 5 }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+
+        let objects = storage
+            .resolve_scope("SELECT * FROM valves", &ScopeOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(prefixes(&objects[0]), ["", "controller"]);
+        let cycles: Vec<_> = objects[0]
+            .cycles
+            .iter()
+            .map(|c| (c.prefix.as_str(), c.table.as_str(), c.key.clone()))
+            .collect();
+        assert_eq!(cycles, [("controller.valve", "valves", json!(5))]);
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::load::parse_data::{DataFile, Locate, load_file};
+use crate::load::parse_tables::SchemaConfig;
+
+/// File and in-file row of every row of a data set merged from several files
+#[derive(Default)]
+pub struct DataSources {
+    files: Vec<(PathBuf, Box<dyn Locate>)>,
+    /// Per table, the file index and the row index within that file of every merged row
+    rows: HashMap<String, Vec<(usize, usize)>>,
+}
+
+impl Locate for DataSources {
+    fn locate(&self, table: &str, row: usize, column: Option<&str>) -> Option<String> {
+        let (file, inner_row) = *self.rows.get(table)?.get(row)?;
+        let (path, inner) = &self.files[file];
+        let inner = inner
+            .locate(table, inner_row, column)
+            .unwrap_or_else(|| format!("row {inner_row}"));
+        Some(format!("{}, {}", path.display(), inner))
+    }
+}
+
+/// Loads every data file below `dir` and merges them into one data set.
+/// Files are read in path order; a primary key defined in two files is an error.
+pub fn load_dir(dir: &Path, schema: &SchemaConfig) -> Result<(DataFile, DataSources)> {
+    let mut paths = Vec::new();
+    collect_files(dir, &mut paths)?;
+    paths.sort();
+
+    let mut data = DataFile::new();
+    let mut sources = DataSources::default();
+    let mut keys: HashMap<String, HashMap<Value, usize>> = HashMap::new();
+
+    for path in paths {
+        let Some((file_data, locate)) = load_file(&path, schema)? else {
+            log::debug!("Skipping {:?}, not a data file", path);
+            continue;
+        };
+        let file = sources.files.len();
+        let display = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+        sources.files.push((display, locate));
+
+        for (table_name, rows) in file_data {
+            let primary_key = schema
+                .tables
+                .iter()
+                .find(|t| t.name == table_name)
+                .map(|t| t.primary_key.as_str());
+            let table_keys = keys.entry(table_name.clone()).or_default();
+            let table_rows = data.entry(table_name.clone()).or_default();
+
+            for (inner_row, row) in rows.into_iter().enumerate() {
+                let index = table_rows.len();
+                sources
+                    .rows
+                    .entry(table_name.clone())
+                    .or_default()
+                    .push((file, inner_row));
+
+                if let Some(pk) = primary_key.and_then(|pk| row.get(pk))
+                    && let Some(first) = table_keys.insert(pk.clone(), index)
+                {
+                    return Err(anyhow!(
+                        "Table `{}`: primary key {} defined in {} and again in {}",
+                        table_name,
+                        pk,
+                        sources.locate(&table_name, first, None).unwrap_or_default(),
+                        sources.locate(&table_name, index, None).unwrap_or_default()
+                    ));
+                }
+                table_rows.push(row);
+            }
+        }
+    }
+
+    Ok((data, sources))
+}
+
+fn collect_files(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).map_err(|e| anyhow!("{:?}: {e}", dir))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, out)?;
+        } else {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use serde_json::json;
+
+    fn schema() -> SchemaConfig {
+        serde_json::from_value(json!({
+            "tables": [{
+                "name": "io",
+                "primary_key": "id",
+                "columns": [{ "name": "id", "type": "text" }, { "name": "rack", "type": "int" }]
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn merges_files_and_locates_rows() {
+        let dir = TempDir::new(
+            "merge",
+            &[
+                ("io.csv", "id,rack\nIO_1,0\n"),
+                ("area1/io.json", r#"[{ "id": "IO_2", "rack": "one" }]"#),
+            ],
+        );
+
+        let (data, sources) = load_dir(dir.path(), &schema()).unwrap();
+        assert_eq!(data["io"].len(), 2);
+        assert_eq!(
+            sources.locate("io", 0, Some("rack")).unwrap(),
+            format!("{}, row 0", Path::new("area1").join("io.json").display())
+        );
+        assert_eq!(
+            sources.locate("io", 1, Some("rack")).unwrap(),
+            "io.csv, line 2, column `rack`"
+        );
+    }
+
+    #[test]
+    fn rejects_keys_defined_in_two_files() {
+        let dir = TempDir::new(
+            "collide",
+            &[
+                ("a.json", r#"{ "io": [{ "id": "IO_1" }] }"#),
+                ("b/io.json", r#"[{ "id": "IO_2" }, { "id": "IO_1" }]"#),
+            ],
+        );
+
+        let err = load_dir(dir.path(), &schema())
+            .map(|_| ())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("a.json, row 0"), "{err}");
+        assert!(err.contains("io.json, row 1"), "{err}");
+    }
+}
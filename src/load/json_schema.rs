@@ -0,0 +1,94 @@
+use serde_json::{Map, Value, json};
+
+use crate::load::parse_tables::{ColumnConfig, SchemaConfig, TableConfig};
+
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// Builds a JSON Schema document describing a data.json valid for `schema`
+pub fn export_json_schema(schema: &SchemaConfig) -> Value {
+    let tables: Map<String, Value> = schema
+        .tables
+        .iter()
+        .map(|t| (t.name.clone(), table_schema(t)))
+        .collect();
+
+    json!({
+        "$schema": DRAFT,
+        "title": "traverse data",
+        "type": "object",
+        "properties": tables,
+        "additionalProperties": false,
+    })
+}
+
+fn table_schema(table: &TableConfig) -> Value {
+    let columns: Map<String, Value> = table
+        .columns
+        .iter()
+        .filter(|c| !c.is_computed())
+        .map(|c| (c.name.clone(), column_schema(c)))
+        .collect();
+
+    json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "properties": columns,
+            "required": [table.primary_key],
+            "additionalProperties": false,
+        },
+    })
+}
+
+fn column_schema(column: &ColumnConfig) -> Value {
+    let json_type = match column.col_type.as_str() {
+        "int" => "integer",
+        "float" => "number",
+        "bool" => "boolean",
+        _ => "string",
+    };
+
+    let mut out = Map::new();
+    out.insert("type".into(), json_type.into());
+    if let Some(allowed) = &column.allowed {
+        out.insert("enum".into(), allowed.clone().into());
+    }
+    if let Some(pattern) = column.anchored_pattern() {
+        out.insert("pattern".into(), pattern.into());
+    }
+    Value::Object(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_tables_columns_and_constraints() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [{
+                "name": "io",
+                "primary_key": "id",
+                "columns": [
+                    { "name": "id", "type": "text", "pattern": "IO_[A-Z]+_\\d+" },
+                    { "name": "rack", "type": "int", "enum": [0, 1, 2] },
+                    { "name": "scale", "type": "float" },
+                    { "name": "inverted", "type": "bool" },
+                    { "name": "label", "type": "text", "expr": "'R' || rack" }
+                ]
+            }]
+        }))
+        .unwrap();
+
+        let doc = export_json_schema(&schema);
+        let item = &doc["properties"]["io"]["items"];
+
+        assert_eq!(doc["properties"]["io"]["type"], "array");
+        assert_eq!(item["required"], json!(["id"]));
+        assert_eq!(item["properties"]["id"]["pattern"], "^(?:IO_[A-Z]+_\\d+)$");
+        assert_eq!(item["properties"]["rack"]["enum"], json!([0, 1, 2]));
+        assert_eq!(item["properties"]["scale"]["type"], "number");
+        assert_eq!(item["properties"]["inverted"]["type"], "boolean");
+        assert!(item["properties"].get("label").is_none());
+    }
+}
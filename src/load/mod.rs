@@ -1,5 +1,11 @@
+pub mod data_dir;
+pub mod json_schema;
+pub mod overlay;
+pub mod parse_csv;
 pub mod parse_data;
 pub mod parse_tables;
+pub mod parse_xlsx;
 
+pub use json_schema::export_json_schema;
 pub use parse_data::load_data;
 pub use parse_tables::load_config;
@@ -0,0 +1,208 @@
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::load::parse_data::{DataFile, Locate, Row};
+use crate::load::parse_tables::SchemaConfig;
+
+/// Site specific changes applied on top of the base data set.
+/// Rows are deleted first, then patched, then added.
+#[derive(Debug, Deserialize, Default)]
+pub struct Overlay {
+    /// Rows to append, per table
+    #[serde(default)]
+    pub add: HashMap<String, Vec<Row>>,
+    /// Primary key values of rows to remove, per table
+    #[serde(default)]
+    pub delete: HashMap<String, Vec<Value>>,
+    /// Partial rows holding the primary key and the columns to change; `null` removes a column
+    #[serde(default)]
+    pub patch: HashMap<String, Vec<Row>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Origin {
+    Base(usize),
+    Added { overlay: usize, row: usize },
+}
+
+/// Keeps rows pointing at the base file they came from, or at the overlay that added them
+pub struct OverlaySources {
+    base: Box<dyn Locate>,
+    overlays: Vec<PathBuf>,
+    rows: HashMap<String, Vec<Origin>>,
+}
+
+impl Locate for OverlaySources {
+    fn locate(&self, table: &str, row: usize, column: Option<&str>) -> Option<String> {
+        match *self.rows.get(table)?.get(row)? {
+            Origin::Base(row) => self.base.locate(table, row, column),
+            Origin::Added { overlay, row } => Some(format!(
+                "{}, add `{}` row {}",
+                self.overlays[overlay].display(),
+                table,
+                row
+            )),
+        }
+    }
+}
+
+pub fn load_overlay(path: &Path) -> Result<Overlay> {
+    let json = std::fs::read_to_string(path).map_err(|e| anyhow!("{:?}: {e}", path))?;
+    serde_json::from_str(&json).map_err(|e| anyhow!("{:?}: {e}", path))
+}
+
+/// Applies each overlay file in order to the base data set
+pub fn apply_overlays(
+    mut data: DataFile,
+    base: Box<dyn Locate>,
+    overlays: &[PathBuf],
+    schema: &SchemaConfig,
+) -> Result<(DataFile, Box<dyn Locate>)> {
+    if overlays.is_empty() {
+        return Ok((data, base));
+    }
+
+    let mut rows: HashMap<String, Vec<Origin>> = data
+        .iter()
+        .map(|(t, r)| (t.clone(), (0..r.len()).map(Origin::Base).collect()))
+        .collect();
+
+    for (index, path) in overlays.iter().enumerate() {
+        let overlay = load_overlay(path)?;
+        apply(&mut data, &mut rows, index, &overlay, schema)
+            .map_err(|e| anyhow!("Overlay {:?}: {e}", path))?;
+        log::info!("Applied overlay {:?}", path);
+    }
+
+    let sources = OverlaySources {
+        base,
+        overlays: overlays.to_vec(),
+        rows,
+    };
+    Ok((data, Box::new(sources)))
+}
+
+fn apply(
+    data: &mut DataFile,
+    origins: &mut HashMap<String, Vec<Origin>>,
+    index: usize,
+    overlay: &Overlay,
+    schema: &SchemaConfig,
+) -> Result<()> {
+    let primary_key = |table: &str| {
+        schema
+            .tables
+            .iter()
+            .find(|t| t.name == table)
+            .map(|t| t.primary_key.clone())
+            .ok_or_else(|| anyhow!("unknown table `{}`", table))
+    };
+    let position =
+        |rows: &[Row], pk: &str, value: &Value| rows.iter().position(|r| r.get(pk) == Some(value));
+
+    for (table, keys) in &overlay.delete {
+        let pk = primary_key(table)?;
+        let rows = data.entry(table.clone()).or_default();
+        let table_origins = origins.entry(table.clone()).or_default();
+        for key in keys {
+            let i = position(rows, &pk, key)
+                .ok_or_else(|| anyhow!("cannot delete `{}` {}, no such row", table, key))?;
+            rows.remove(i);
+            table_origins.remove(i);
+        }
+    }
+
+    for (table, patches) in &overlay.patch {
+        let pk = primary_key(table)?;
+        let rows = data.entry(table.clone()).or_default();
+        for patch in patches {
+            let key = patch
+                .get(&pk)
+                .ok_or_else(|| anyhow!("patch for `{}` is missing primary key `{}`", table, pk))?;
+            let i = position(rows, &pk, key)
+                .ok_or_else(|| anyhow!("cannot patch `{}` {}, no such row", table, key))?;
+            for (column, value) in patch {
+                if value.is_null() {
+                    rows[i].remove(column);
+                } else {
+                    rows[i].insert(column.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    for (table, added) in &overlay.add {
+        let pk = primary_key(table)?;
+        let rows = data.entry(table.clone()).or_default();
+        let table_origins = origins.entry(table.clone()).or_default();
+        for (row_index, row) in added.iter().enumerate() {
+            if let Some(key) = row.get(&pk)
+                && position(rows, &pk, key).is_some()
+            {
+                return Err(anyhow!(
+                    "cannot add `{}` {}, row already exists",
+                    table,
+                    key
+                ));
+            }
+            rows.push(row.clone());
+            table_origins.push(Origin::Added {
+                overlay: index,
+                row: row_index,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn deletes_patches_and_adds_rows() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [{
+                "name": "io",
+                "primary_key": "id",
+                "columns": [{ "name": "id", "type": "text" }, { "name": "rack", "type": "int" }]
+            }]
+        }))
+        .unwrap();
+        let mut data: DataFile = serde_json::from_value(json!({
+            "io": [{ "id": "A", "rack": 0 }, { "id": "B", "rack": 0 }, { "id": "C", "rack": 0 }]
+        }))
+        .unwrap();
+        let overlay: Overlay = serde_json::from_value(json!({
+            "delete": { "io": ["B"] },
+            "patch": { "io": [{ "id": "C", "rack": 2 }, { "id": "A", "rack": null }] },
+            "add": { "io": [{ "id": "D", "rack": 1 }] }
+        }))
+        .unwrap();
+
+        let mut origins = HashMap::from([(
+            "io".to_string(),
+            (0..3).map(Origin::Base).collect::<Vec<_>>(),
+        )]);
+        apply(&mut data, &mut origins, 0, &overlay, &schema).unwrap();
+
+        assert_eq!(
+            Value::from(data["io"].clone()),
+            json!([{ "id": "A" }, { "id": "C", "rack": 2 }, { "id": "D", "rack": 1 }])
+        );
+        assert!(matches!(origins["io"][1], Origin::Base(2)));
+        assert!(matches!(
+            origins["io"][2],
+            Origin::Added { overlay: 0, row: 0 }
+        ));
+
+        let twice: Overlay =
+            serde_json::from_value(json!({ "add": { "io": [{ "id": "A" }] } })).unwrap();
+        assert!(apply(&mut data, &mut origins, 1, &twice, &schema).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::{Result, anyhow};
+use serde_json::{Number, Value};
+use std::io::Read;
+use std::path::Path;
+
+use crate::load::parse_data::{DataFile, Locate, Row};
+use crate::load::parse_tables::{SchemaConfig, TableConfig};
+
+/// Line numbers of the rows read from a CSV file
+#[derive(Debug)]
+pub struct CsvLines {
+    table: String,
+    lines: Vec<u64>,
+}
+
+impl Locate for CsvLines {
+    fn locate(&self, table: &str, row: usize, column: Option<&str>) -> Option<String> {
+        if table != self.table {
+            return None;
+        }
+        let line = self.lines.get(row)?;
+        Some(match column {
+            Some(c) => format!("line {line}, column `{c}`"),
+            None => format!("line {line}"),
+        })
+    }
+}
+
+/// Reads a `<table>.csv` file into rows typed after the schema columns
+pub fn load_csv(path: &Path, schema: &SchemaConfig) -> Result<(DataFile, CsvLines)> {
+    let table_name = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow!("Invalid CSV file name {:?}", path))?;
+    let table = schema
+        .tables
+        .iter()
+        .find(|t| t.name == table_name)
+        .ok_or_else(|| anyhow!("{:?}: no table `{}` in schema", path, table_name))?;
+
+    let file = std::fs::File::open(path).map_err(|e| anyhow!("{:?}: {e}", path))?;
+    let (rows, lines) = parse_csv(file, table).map_err(|e| anyhow!("{:?}: {e}", path))?;
+    let data = DataFile::from([(table.name.clone(), rows)]);
+    Ok((
+        data,
+        CsvLines {
+            table: table.name.clone(),
+            lines,
+        },
+    ))
+}
+
+/// Parses CSV with a header row of column names, empty cells are left out of the row.
+/// Returns the rows along with the line each of them starts on.
+pub fn parse_csv(reader: impl Read, table: &TableConfig) -> Result<(Vec<Row>, Vec<u64>)> {
+    let mut csv = csv::Reader::from_reader(reader);
+    let headers = csv.headers()?.clone();
+
+    let mut columns = Vec::with_capacity(headers.len());
+    for name in headers.iter() {
+        let column = table
+            .columns
+            .iter()
+            .find(|c| c.name == name.trim())
+            .ok_or_else(|| anyhow!("unknown column `{}` in header", name))?;
+        columns.push(column);
+    }
+
+    let mut rows = Vec::new();
+    let mut lines = Vec::new();
+    for record in csv.records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+
+        let mut row = Row::new();
+        for (column, cell) in columns.iter().zip(record.iter()) {
+            let cell = cell.trim();
+            if cell.is_empty() {
+                continue;
+            }
+            let value = parse_cell(&column.col_type, cell).ok_or_else(|| {
+                anyhow!(
+                    "line {}, column `{}`: expected {}, got `{}`",
+                    line,
+                    column.name,
+                    column.col_type,
+                    cell
+                )
+            })?;
+            row.insert(column.name.clone(), value);
+        }
+        rows.push(row);
+        lines.push(line);
+    }
+
+    Ok((rows, lines))
+}
+
+/// Converts a spreadsheet cell to the JSON value `parse_data::validate` expects for `col_type`
+pub fn parse_cell(col_type: &str, cell: &str) -> Option<Value> {
+    match col_type {
+        "int" => cell.parse::<i64>().ok().map(Value::from),
+        "float" => cell
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        "bool" => match cell.to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" => Some(Value::Bool(true)),
+            "false" | "0" | "no" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        _ => Some(Value::String(cell.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn table() -> TableConfig {
+        serde_json::from_value(json!({
+            "name": "io",
+            "primary_key": "id",
+            "columns": [
+                { "name": "id", "type": "text" },
+                { "name": "rack", "type": "int" },
+                { "name": "scale", "type": "float" },
+                { "name": "spare", "type": "bool" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_cells_by_column_type() {
+        let csv = "id,rack,scale,spare\nIO_1,0,1.5,no\nIO_2,1,,TRUE\n";
+        let (rows, lines) = parse_csv(csv.as_bytes(), &table()).unwrap();
+
+        assert_eq!(
+            Value::from(rows),
+            json!([
+                { "id": "IO_1", "rack": 0, "scale": 1.5, "spare": false },
+                { "id": "IO_2", "rack": 1, "spare": true }
+            ])
+        );
+        assert_eq!(lines, [2, 3]);
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        let csv = "id,rack\nIO_1,0\nIO_2,two\n";
+        let err = parse_csv(csv.as_bytes(), &table()).unwrap_err().to_string();
+        assert_eq!(err, "line 3, column `rack`: expected int, got `two`");
+
+        let err = parse_csv("id,slot\n".as_bytes(), &table())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown column `slot`"), "{err}");
+    }
+}
@@ -1,21 +1,99 @@
 use anyhow::{Result, anyhow};
+use regex::Regex;
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use crate::load::data_dir::load_dir;
+use crate::load::overlay::apply_overlays;
+use crate::load::parse_csv::load_csv;
 use crate::load::parse_tables::SchemaConfig;
+use crate::load::parse_xlsx::load_xlsx;
 
-type Row = serde_json::Map<String, Value>;
+pub type Row = serde_json::Map<String, Value>;
 pub type DataFile = HashMap<String, Vec<Row>>;
 
-pub fn load_data(dir: &Path, schema: &SchemaConfig) -> Result<DataFile> {
-    let json = std::fs::read_to_string(dir).map_err(|e| anyhow::anyhow!("{e}"))?;
-    let data: DataFile = serde_json::from_str(&json)?;
-    validate(&data, schema)?;
+/// Maps a row, and optionally one of its columns, back to where it was read from
+pub trait Locate {
+    fn locate(&self, table: &str, row: usize, column: Option<&str>) -> Option<String>;
+}
+
+/// Rows of a plain data.json are only known by their index
+impl Locate for () {
+    fn locate(&self, _: &str, _: usize, _: Option<&str>) -> Option<String> {
+        None
+    }
+}
+
+fn at(sources: &dyn Locate, table: &str, row: usize, column: Option<&str>) -> String {
+    sources
+        .locate(table, row, column)
+        .map(|loc| format!(" (at {loc})"))
+        .unwrap_or_default()
+}
+
+/// Loads a data.json file, a `<table>.csv` file, an .xlsx workbook,
+/// or a directory of any of them merged into one data set,
+/// then applies the overlay files in order before validating
+pub fn load_data(dir: &Path, schema: &SchemaConfig, overlays: &[PathBuf]) -> Result<DataFile> {
+    let (data, sources) = if dir.is_dir() {
+        let (data, sources) = load_dir(dir, schema)?;
+        (data, Box::new(sources) as Box<dyn Locate>)
+    } else {
+        load_file(dir, schema)?.ok_or_else(|| {
+            anyhow!(
+                "{:?}: unsupported data file, expected .json, .csv or .xlsx",
+                dir
+            )
+        })?
+    };
+    let (data, sources) = apply_overlays(data, sources, overlays, schema)?;
+    validate(&data, schema, &*sources)?;
     Ok(data)
 }
 
-fn validate(data: &DataFile, schema: &SchemaConfig) -> Result<()> {
+/// Reads a single data file by extension, `None` when the extension is not a data format.
+/// A JSON file holds either tables keyed by name, or the rows of the table it is named after.
+pub fn load_file(
+    path: &Path,
+    schema: &SchemaConfig,
+) -> Result<Option<(DataFile, Box<dyn Locate>)>> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    let loaded: (DataFile, Box<dyn Locate>) = match extension.as_deref() {
+        Some("json") => {
+            let json = std::fs::read_to_string(path).map_err(|e| anyhow!("{:?}: {e}", path))?;
+            let value: Value =
+                serde_json::from_str(&json).map_err(|e| anyhow!("{:?}: {e}", path))?;
+            let data = match value {
+                Value::Array(_) => {
+                    let table = path
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .ok_or_else(|| anyhow!("Invalid JSON file name {:?}", path))?;
+                    DataFile::from([(table.to_string(), serde_json::from_value(value)?)])
+                }
+                _ => serde_json::from_value(value).map_err(|e| anyhow!("{:?}: {e}", path))?,
+            };
+            (data, Box::new(()))
+        }
+        Some("csv") => {
+            let (data, lines) = load_csv(path, schema)?;
+            (data, Box::new(lines))
+        }
+        Some("xlsx") => {
+            let (data, sheets) = load_xlsx(path, schema)?;
+            (data, Box::new(sheets))
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(loaded))
+}
+
+pub fn validate(data: &DataFile, schema: &SchemaConfig, sources: &dyn Locate) -> Result<()> {
     let table_map: HashMap<_, _> = schema.tables.iter().map(|t| (&t.name, t)).collect();
 
     for (table_name, rows) in data {
@@ -23,42 +101,88 @@ fn validate(data: &DataFile, schema: &SchemaConfig) -> Result<()> {
             .get(table_name)
             .ok_or_else(|| anyhow!("Data contains unknown table `{}`", table_name))?;
 
-        let column_map: HashMap<_, _> = table
+        let column_map: HashMap<_, _> = table.columns.iter().map(|c| (&c.name, c)).collect();
+        let patterns = table
             .columns
             .iter()
-            .map(|c| (&c.name, &c.col_type))
-            .collect();
+            .filter_map(|c| c.anchored_pattern().map(|p| (&c.name, p)))
+            .map(|(name, p)| Ok((name, Regex::new(&p)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
 
         let mut seen_pks = HashSet::new();
 
         for (row_index, row) in rows.iter().enumerate() {
             for (col_name, value) in row {
-                let col_type = column_map.get(col_name).ok_or_else(|| {
+                let column = column_map.get(col_name).ok_or_else(|| {
                     anyhow!(
-                        "Table `{}` row {}: unknown column `{}`",
+                        "Table `{}` row {}: unknown column `{}`{}",
                         table_name,
                         row_index,
-                        col_name
+                        col_name,
+                        at(sources, table_name, row_index, Some(col_name))
                     )
                 })?;
 
-                validate_type(table_name, row_index, col_name, col_type, value)?;
+                if column.is_computed() {
+                    return Err(anyhow!(
+                        "Table `{}` row {}: column `{}` is computed and cannot be set{}",
+                        table_name,
+                        row_index,
+                        col_name,
+                        at(sources, table_name, row_index, Some(col_name))
+                    ));
+                }
+
+                validate_type(table_name, row_index, col_name, &column.col_type, value).map_err(
+                    |e| anyhow!("{e}{}", at(sources, table_name, row_index, Some(col_name))),
+                )?;
+
+                if let Some(allowed) = &column.allowed
+                    && !allowed.contains(value)
+                {
+                    return Err(anyhow!(
+                        "Table `{}` row {}: column `{}` value {} is not one of {}{}",
+                        table_name,
+                        row_index,
+                        col_name,
+                        value,
+                        Value::from(allowed.clone()),
+                        at(sources, table_name, row_index, Some(col_name))
+                    ));
+                }
+
+                if let Some(re) = patterns.get(col_name)
+                    && let Some(s) = value.as_str()
+                    && !re.is_match(s)
+                {
+                    return Err(anyhow!(
+                        "Table `{}` row {}: column `{}` value {} does not match pattern `{}`{}",
+                        table_name,
+                        row_index,
+                        col_name,
+                        value,
+                        re.as_str(),
+                        at(sources, table_name, row_index, Some(col_name))
+                    ));
+                }
             }
 
             let pk_value = row.get(&table.primary_key).ok_or_else(|| {
                 anyhow!(
-                    "Table `{}` row {}: missing primary key `{}`",
+                    "Table `{}` row {}: missing primary key `{}`{}",
                     table_name,
                     row_index,
-                    table.primary_key
+                    table.primary_key,
+                    at(sources, table_name, row_index, None)
                 )
             })?;
 
             if !seen_pks.insert(pk_value.clone()) {
                 return Err(anyhow!(
-                    "Table `{}`: duplicate primary key value `{}`",
+                    "Table `{}`: duplicate primary key value `{}`{}",
                     table_name,
-                    pk_value
+                    pk_value,
+                    at(sources, table_name, row_index, Some(&table.primary_key))
                 ));
             }
         }
@@ -83,18 +207,19 @@ fn validate(data: &DataFile, schema: &SchemaConfig) -> Result<()> {
                 }
 
                 for (i, row) in rows.iter().enumerate() {
-                    if let Some(v) = row.get(&fk.column) {
-                        if !target_values.contains(v) {
-                            return Err(anyhow!(
-                                "FK violation: `{}`.`{}` = {} (row {}) does not exist in `{}`.`{}`",
-                                table.name,
-                                fk.column,
-                                v,
-                                i,
-                                fk.references.table,
-                                fk.references.column
-                            ));
-                        }
+                    if let Some(v) = row.get(&fk.column)
+                        && !target_values.contains(v)
+                    {
+                        return Err(anyhow!(
+                            "FK violation: `{}`.`{}` = {} (row {}) does not exist in `{}`.`{}`{}",
+                            table.name,
+                            fk.column,
+                            v,
+                            i,
+                            fk.references.table,
+                            fk.references.column,
+                            at(sources, &table.name, i, Some(&fk.column))
+                        ));
                     }
                 }
             }
@@ -104,16 +229,19 @@ fn validate(data: &DataFile, schema: &SchemaConfig) -> Result<()> {
     Ok(())
 }
 
-fn validate_type(table: &str, row: usize, col: &str, col_type: &str, value: &Value) -> Result<()> {
-    let ok = match col_type {
+pub(crate) fn matches_type(col_type: &str, value: &Value) -> bool {
+    match col_type {
         "int" => value.is_i64() || value.is_u64(),
-        "float" => value.is_f64(),
+        // JSON Schema `number` takes `2` as well, it is stored as REAL
+        "float" => value.is_number(),
         "text" => value.is_string(),
         "bool" => value.is_boolean(),
         _ => false,
-    };
+    }
+}
 
-    if ok {
+fn validate_type(table: &str, row: usize, col: &str, col_type: &str, value: &Value) -> Result<()> {
+    if matches_type(col_type, value) {
         Ok(())
     } else {
         Err(anyhow!(
@@ -126,3 +254,52 @@ fn validate_type(table: &str, row: usize, col: &str, col_type: &str, value: &Val
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> SchemaConfig {
+        serde_json::from_value(json!({
+            "tables": [{
+                "name": "io",
+                "primary_key": "id",
+                "columns": [
+                    { "name": "id", "type": "text" },
+                    { "name": "db", "type": "int" },
+                    { "name": "scale", "type": "float" },
+                    { "name": "label", "type": "text", "expr": "'DB' || db" }
+                ]
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_values_for_computed_columns() {
+        let ok: DataFile =
+            serde_json::from_value(json!({ "io": [{ "id": "A", "db": 1 }] })).unwrap();
+        assert!(validate(&ok, &schema(), &()).is_ok());
+
+        let bad: DataFile =
+            serde_json::from_value(json!({ "io": [{ "id": "A", "db": 1, "label": "DB1" }] }))
+                .unwrap();
+        let err = validate(&bad, &schema(), &()).unwrap_err().to_string();
+        assert!(err.contains("computed"), "{err}");
+    }
+
+    #[test]
+    fn accepts_integers_for_float_columns() {
+        for scale in [json!(2), json!(2.5)] {
+            let data: DataFile =
+                serde_json::from_value(json!({ "io": [{ "id": "A", "scale": scale }] })).unwrap();
+            assert!(validate(&data, &schema(), &()).is_ok(), "{scale}");
+        }
+
+        let bad: DataFile =
+            serde_json::from_value(json!({ "io": [{ "id": "A", "scale": "2" }] })).unwrap();
+        let err = validate(&bad, &schema(), &()).unwrap_err().to_string();
+        assert!(err.contains("expected float"), "{err}");
+    }
+}
@@ -6,22 +6,84 @@ use std::path::Path;
 #[derive(Debug, Deserialize, Clone)]
 pub struct SchemaConfig {
     pub tables: Vec<TableConfig>,
+    #[serde(default)]
+    pub views: Vec<ViewConfig>,
+    #[serde(default)]
+    pub column_groups: Vec<ColumnGroupConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct TableConfig {
     pub name: String,
     pub primary_key: String,
+    /// Column groups whose columns and foreign keys are copied into this table
+    #[serde(default)]
+    pub extends: Vec<String>,
+    #[serde(default)]
+    pub columns: Vec<ColumnConfig>,
+    #[serde(default)]
+    pub foreign_keys: Vec<ForeignKeyConfig>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+/// Columns and foreign keys shared by several tables through `extends`
+#[derive(Debug, Deserialize, Clone)]
+pub struct ColumnGroupConfig {
+    pub name: String,
+    #[serde(default)]
+    pub extends: Vec<String>,
+    #[serde(default)]
     pub columns: Vec<ColumnConfig>,
     #[serde(default)]
     pub foreign_keys: Vec<ForeignKeyConfig>,
 }
 
+/// Named SELECT created in SQLite after the tables, usable like a table in scripts
 #[derive(Debug, Deserialize, Clone)]
+pub struct ViewConfig {
+    pub name: String,
+    pub sql: String,
+    pub key: String,
+    #[serde(default)]
+    pub foreign_keys: Vec<ForeignKeyConfig>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct ColumnConfig {
     pub name: String,
     #[serde(rename = "type")]
     pub col_type: String,
+    #[serde(default, rename = "enum")]
+    pub allowed: Option<Vec<serde_json::Value>>,
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// SQL expression over other columns of the row, stored as a generated column
+    #[serde(default)]
+    pub expr: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+impl ColumnConfig {
+    pub fn is_computed(&self) -> bool {
+        self.expr.is_some()
+    }
+
+    /// `pattern` anchored to match the whole value, as in JSON Schema output and validation
+    pub fn anchored_pattern(&self) -> Option<String> {
+        self.pattern.as_ref().map(|p| format!("^(?:{p})$"))
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -38,20 +100,123 @@ pub struct ReferenceConfig {
 
 pub fn load_config(dir: &Path) -> Result<SchemaConfig> {
     let json = std::fs::read_to_string(dir).expect("Failed to read config file");
-    let schema: SchemaConfig =
+    let mut schema: SchemaConfig =
         serde_json::from_str(&json).expect("Failed to serialize the config, unexpected pattern");
 
+    expand_groups(&mut schema).map_err(|e| anyhow!(e))?;
     match validate(&schema) {
         Ok(_) => Ok(schema),
         Err(e) => Err(anyhow!(e)),
     }
 }
+/// Copies the columns and foreign keys of every extended group into its tables.
+/// Inherited columns come first; a table column with the same name replaces the inherited one.
+/// A group reached through several paths is copied once, two groups defining the same
+/// column or foreign key are an error.
+fn expand_groups(schema: &mut SchemaConfig) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for group in &schema.column_groups {
+        if !seen.insert(group.name.as_str()) {
+            return Err(format!("Column group {}: defined twice", group.name));
+        }
+    }
+
+    for table in &mut schema.tables {
+        if table.extends.is_empty() {
+            continue;
+        }
+        let mut columns: Vec<ColumnConfig> = Vec::new();
+        let mut foreign_keys: Vec<ForeignKeyConfig> = Vec::new();
+        let mut copied = HashSet::new();
+        for name in &table.extends {
+            collect_group(
+                &schema.column_groups,
+                name,
+                &mut Vec::new(),
+                &mut copied,
+                &mut columns,
+                &mut foreign_keys,
+            )
+            .map_err(|e| format!("Table {}: {}", table.name, e))?;
+        }
+
+        for col in table.columns.drain(..) {
+            match columns.iter_mut().find(|c| c.name == col.name) {
+                Some(inherited) => *inherited = col,
+                None => columns.push(col),
+            }
+        }
+        for fk in table.foreign_keys.drain(..) {
+            match foreign_keys.iter_mut().find(|f| f.column == fk.column) {
+                Some(inherited) => *inherited = fk,
+                None => foreign_keys.push(fk),
+            }
+        }
+        table.columns = columns;
+        table.foreign_keys = foreign_keys;
+    }
+    Ok(())
+}
+
+fn collect_group<'a>(
+    groups: &'a [ColumnGroupConfig],
+    name: &'a str,
+    stack: &mut Vec<&'a str>,
+    copied: &mut HashSet<&'a str>,
+    columns: &mut Vec<ColumnConfig>,
+    foreign_keys: &mut Vec<ForeignKeyConfig>,
+) -> Result<(), String> {
+    if stack.contains(&name) {
+        return Err(format!(
+            "column group cycle {} -> {}",
+            stack.join(" -> "),
+            name
+        ));
+    }
+    let group = groups
+        .iter()
+        .find(|g| g.name == name)
+        .ok_or_else(|| format!("column group {} not found", name))?;
+    if !copied.insert(name) {
+        return Ok(());
+    }
+
+    stack.push(name);
+    for parent in &group.extends {
+        collect_group(groups, parent, stack, copied, columns, foreign_keys)?;
+    }
+    stack.pop();
+
+    for col in &group.columns {
+        if columns.iter().any(|c| c.name == col.name) {
+            return Err(format!(
+                "column {} inherited more than once (group {})",
+                col.name, name
+            ));
+        }
+        columns.push(col.clone());
+    }
+    for fk in &group.foreign_keys {
+        if foreign_keys.iter().any(|f| f.column == fk.column) {
+            return Err(format!(
+                "foreign key on {} inherited more than once (group {})",
+                fk.column, name
+            ));
+        }
+        foreign_keys.push(fk.clone());
+    }
+    Ok(())
+}
+
 fn validate(schema: &SchemaConfig) -> Result<(), String> {
     let table_names: HashSet<_> = schema.tables.iter().map(|t| t.name.as_str()).collect();
 
     for table in &schema.tables {
         let col_names: HashSet<_> = table.columns.iter().map(|c| c.name.as_str()).collect();
 
+        validate_text(&table.name, "description", &table.description)?;
+        validate_text(&table.name, "label", &table.label)?;
+
         if !col_names.contains(table.primary_key.as_str()) {
             return Err(format!(
                 "Table {}: primary key {} not in columns",
@@ -59,7 +224,67 @@ fn validate(schema: &SchemaConfig) -> Result<(), String> {
             ));
         }
 
+        for col in &table.columns {
+            let owner = format!("{}.{}", table.name, col.name);
+            validate_text(&owner, "description", &col.description)?;
+            validate_text(&owner, "label", &col.label)?;
+            validate_text(&owner, "unit", &col.unit)?;
+            if col.unit.is_some() && !matches!(col.col_type.as_str(), "int" | "float") {
+                return Err(format!(
+                    "Table {}: unit on column {} requires a numeric type",
+                    table.name, col.name
+                ));
+            }
+
+            if col.is_computed() && col.name == table.primary_key {
+                return Err(format!(
+                    "Table {}: primary key {} cannot be a computed column",
+                    table.name, col.name
+                ));
+            }
+            if !matches!(col.col_type.as_str(), "int" | "float" | "text" | "bool") {
+                return Err(format!(
+                    "Table {}: column {} has unknown type {}",
+                    table.name, col.name, col.col_type
+                ));
+            }
+            if let Some(allowed) = &col.allowed
+                && let Some(v) = allowed
+                    .iter()
+                    .find(|v| !super::parse_data::matches_type(&col.col_type, v))
+            {
+                return Err(format!(
+                    "Table {}: enum value {} of column {} is not of type {}",
+                    table.name, v, col.name, col.col_type
+                ));
+            }
+            if let Some(pattern) = col.anchored_pattern() {
+                if col.col_type != "text" {
+                    return Err(format!(
+                        "Table {}: pattern on column {} requires type text",
+                        table.name, col.name
+                    ));
+                }
+                if let Err(e) = regex::Regex::new(&pattern) {
+                    return Err(format!(
+                        "Table {}: invalid pattern on column {}: {}",
+                        table.name, col.name, e
+                    ));
+                }
+            }
+        }
+
         for fk in &table.foreign_keys {
+            if table
+                .columns
+                .iter()
+                .any(|c| c.name == fk.column && c.is_computed())
+            {
+                return Err(format!(
+                    "Table {}: FK column {} cannot be a computed column",
+                    table.name, fk.column
+                ));
+            }
             if !col_names.contains(fk.column.as_str()) {
                 return Err(format!(
                     "Table {}: FK column {} not found",
@@ -74,5 +299,196 @@ fn validate(schema: &SchemaConfig) -> Result<(), String> {
             }
         }
     }
+
+    let mut node_names = table_names.clone();
+    for view in &schema.views {
+        if !node_names.insert(view.name.as_str()) {
+            return Err(format!("View {}: name already in use", view.name));
+        }
+        validate_text(&view.name, "description", &view.description)?;
+        validate_text(&view.name, "label", &view.label)?;
+        if view.key.is_empty() {
+            return Err(format!("View {}: key column is empty", view.name));
+        }
+    }
+    for view in &schema.views {
+        for fk in &view.foreign_keys {
+            if !node_names.contains(fk.references.table.as_str()) {
+                return Err(format!(
+                    "View {}: referenced table {} not found",
+                    view.name, fk.references.table
+                ));
+            }
+        }
+    }
     Ok(())
 }
+
+fn validate_text(owner: &str, field: &str, value: &Option<String>) -> Result<(), String> {
+    match value {
+        Some(v) if v.trim().is_empty() => Err(format!("{owner}: {field} is empty")),
+        Some(v) if v.trim() != v => Err(format!(
+            "{owner}: {field} has leading or trailing whitespace"
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn schema_with_view(view: serde_json::Value) -> SchemaConfig {
+        serde_json::from_value(json!({
+            "tables": [{
+                "name": "io",
+                "primary_key": "id",
+                "columns": [{ "name": "id", "type": "text" }]
+            }],
+            "views": [view]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn validates_views() {
+        let ok = schema_with_view(json!({
+            "name": "inputs",
+            "sql": "SELECT * FROM io",
+            "key": "id",
+            "foreign_keys": [{ "column": "id", "references": { "table": "io", "column": "id" } }]
+        }));
+        assert!(validate(&ok).is_ok());
+
+        let clash = schema_with_view(json!({ "name": "io", "sql": "SELECT 1", "key": "id" }));
+        assert!(validate(&clash).is_err());
+
+        let dangling = schema_with_view(json!({
+            "name": "inputs",
+            "sql": "SELECT * FROM io",
+            "key": "id",
+            "foreign_keys": [{ "column": "id", "references": { "table": "racks", "column": "id" } }]
+        }));
+        assert!(validate(&dangling).is_err());
+    }
+
+    #[test]
+    fn validates_metadata() {
+        let schema = |column: serde_json::Value| -> SchemaConfig {
+            serde_json::from_value(json!({
+                "tables": [{
+                    "name": "io",
+                    "primary_key": "id",
+                    "description": "IO channels",
+                    "columns": [{ "name": "id", "type": "text" }, column]
+                }]
+            }))
+            .unwrap()
+        };
+
+        let ok =
+            schema(json!({ "name": "scale", "type": "float", "unit": "mA", "label": "Scale" }));
+        assert!(validate(&ok).is_ok());
+
+        let text_unit = schema(json!({ "name": "tag", "type": "text", "unit": "mA" }));
+        assert!(validate(&text_unit).is_err());
+
+        let blank = schema(json!({ "name": "tag", "type": "text", "description": " " }));
+        assert!(validate(&blank).is_err());
+    }
+
+    #[test]
+    fn expands_column_groups() {
+        let mut schema: SchemaConfig = serde_json::from_value(json!({
+            "column_groups": [
+                {
+                    "name": "located",
+                    "columns": [{ "name": "area", "type": "text" }]
+                },
+                {
+                    "name": "device",
+                    "extends": ["located"],
+                    "columns": [
+                        { "name": "name", "type": "text" },
+                        { "name": "plc", "type": "int" }
+                    ],
+                    "foreign_keys": [{ "column": "plc", "references": { "table": "plcs", "column": "id" } }]
+                }
+            ],
+            "tables": [
+                {
+                    "name": "plcs",
+                    "primary_key": "id",
+                    "columns": [{ "name": "id", "type": "int" }]
+                },
+                {
+                    "name": "valves",
+                    "primary_key": "name",
+                    "extends": ["device"],
+                    "columns": [
+                        { "name": "plc", "type": "int", "description": "Controlling PLC" },
+                        { "name": "open_fb", "type": "text" }
+                    ]
+                }
+            ]
+        }))
+        .unwrap();
+
+        expand_groups(&mut schema).unwrap();
+        assert!(validate(&schema).is_ok());
+
+        let valves = &schema.tables[1];
+        let names: Vec<_> = valves.columns.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["area", "name", "plc", "open_fb"]);
+        assert_eq!(
+            valves.columns[2].description.as_deref(),
+            Some("Controlling PLC")
+        );
+        assert_eq!(valves.foreign_keys.len(), 1);
+    }
+
+    #[test]
+    fn rejects_group_cycles() {
+        let mut schema: SchemaConfig = serde_json::from_value(json!({
+            "column_groups": [
+                { "name": "a", "extends": ["b"] },
+                { "name": "b", "extends": ["a"] }
+            ],
+            "tables": [{ "name": "t", "primary_key": "id", "extends": ["a"] }]
+        }))
+        .unwrap();
+
+        let err = expand_groups(&mut schema).unwrap_err();
+        assert!(err.contains("cycle"), "{err}");
+    }
+
+    #[test]
+    fn copies_shared_groups_once() {
+        let groups = json!([
+            { "name": "base", "columns": [{ "name": "plc", "type": "int" }],
+              "foreign_keys": [{ "column": "plc", "references": { "table": "plcs", "column": "id" } }] },
+            { "name": "left", "extends": ["base"] },
+            { "name": "right", "extends": ["base"] },
+            { "name": "other", "foreign_keys": [{ "column": "plc", "references": { "table": "plcs", "column": "id" } }] }
+        ]);
+        let expand = |extends: Value| {
+            let mut schema: SchemaConfig = serde_json::from_value(json!({
+                "column_groups": groups,
+                "tables": [{ "name": "t", "primary_key": "id", "extends": extends }]
+            }))
+            .unwrap();
+            expand_groups(&mut schema).map(|_| schema.tables.remove(0))
+        };
+
+        let table = expand(json!(["left", "right"])).unwrap();
+        assert_eq!(table.columns.len(), 1);
+        assert_eq!(table.foreign_keys.len(), 1);
+
+        let err = expand(json!(["base", "other"])).unwrap_err();
+        assert!(
+            err.contains("foreign key on plc inherited more than once"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{Result, anyhow};
+use calamine::{Data, Range, Reader, Xlsx, open_workbook};
+use serde_json::{Number, Value};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::load::parse_csv::parse_cell;
+use crate::load::parse_data::{DataFile, Locate, Row};
+use crate::load::parse_tables::{SchemaConfig, TableConfig};
+
+/// Cell positions of the rows read from each sheet, keyed by table name
+#[derive(Debug, Default)]
+pub struct SheetSources {
+    sheets: HashMap<String, SheetLayout>,
+}
+
+#[derive(Debug, Default)]
+pub struct SheetLayout {
+    sheet: String,
+    /// Absolute column index of every column in the header
+    columns: HashMap<String, u32>,
+    /// Absolute row index of every row pushed into the table
+    rows: Vec<u32>,
+}
+
+impl Locate for SheetSources {
+    fn locate(&self, table: &str, row: usize, column: Option<&str>) -> Option<String> {
+        let layout = self.sheets.get(table)?;
+        let row = *layout.rows.get(row)?;
+        match column.and_then(|c| layout.columns.get(c)) {
+            Some(col) => Some(cell_ref(&layout.sheet, row, *col)),
+            None => Some(format!("{}!{}:{}", layout.sheet, row + 1, row + 1)),
+        }
+    }
+}
+
+/// Reads every sheet named after a schema table, the first row holding column names
+pub fn load_xlsx(path: &Path, schema: &SchemaConfig) -> Result<(DataFile, SheetSources)> {
+    let mut workbook: Xlsx<_> = open_workbook(path).map_err(|e| anyhow!("{:?}: {e}", path))?;
+    let mut data = DataFile::new();
+    let mut sources = SheetSources::default();
+
+    for sheet in workbook.sheet_names() {
+        let Some(table) = schema.tables.iter().find(|t| t.name == sheet) else {
+            log::warn!("{:?}: skipping sheet `{}`, no such table", path, sheet);
+            continue;
+        };
+        let range = workbook
+            .worksheet_range(&sheet)
+            .map_err(|e| anyhow!("{:?}: sheet `{}`: {e}", path, sheet))?;
+
+        let (rows, layout) = parse_sheet(&sheet, &range, table)?;
+        data.insert(table.name.clone(), rows);
+        sources.sheets.insert(table.name.clone(), layout);
+    }
+
+    Ok((data, sources))
+}
+
+pub fn parse_sheet(
+    sheet: &str,
+    range: &Range<Data>,
+    table: &TableConfig,
+) -> Result<(Vec<Row>, SheetLayout)> {
+    let mut layout = SheetLayout {
+        sheet: sheet.to_string(),
+        ..Default::default()
+    };
+    let Some((first_row, first_col)) = range.start() else {
+        return Ok((Vec::new(), layout));
+    };
+    let mut cells = range.rows().zip(first_row..);
+
+    let mut columns = Vec::new();
+    if let Some((header, row)) = cells.next() {
+        for (cell, col) in header.iter().zip(first_col..) {
+            let name = cell.to_string();
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let column = table
+                .columns
+                .iter()
+                .find(|c| c.name == name)
+                .ok_or_else(|| {
+                    anyhow!("{}: unknown column `{}`", cell_ref(sheet, row, col), name)
+                })?;
+            layout.columns.insert(column.name.clone(), col);
+            columns.push((col - first_col, column));
+        }
+    }
+
+    let mut rows = Vec::new();
+    for (cells, row) in cells {
+        if cells.iter().all(is_blank) {
+            continue;
+        }
+
+        let mut out = Row::new();
+        for (index, column) in &columns {
+            let cell = &cells[*index as usize];
+            if is_blank(cell) {
+                continue;
+            }
+            let value = cell_value(&column.col_type, cell).ok_or_else(|| {
+                anyhow!(
+                    "{}: column `{}` expected {}, got `{}`",
+                    cell_ref(sheet, row, first_col + index),
+                    column.name,
+                    column.col_type,
+                    cell
+                )
+            })?;
+            out.insert(column.name.clone(), value);
+        }
+        rows.push(out);
+        layout.rows.push(row);
+    }
+
+    Ok((rows, layout))
+}
+
+fn is_blank(cell: &Data) -> bool {
+    match cell {
+        Data::Empty => true,
+        Data::String(s) => s.trim().is_empty(),
+        _ => false,
+    }
+}
+
+/// Converts a cell to the JSON value expected for `col_type`.
+/// Excel stores every number as a float, whole numbers are accepted for `int` and `text`.
+fn cell_value(col_type: &str, cell: &Data) -> Option<Value> {
+    match (col_type, cell) {
+        (_, Data::String(s)) => parse_cell(col_type, s.trim()),
+        ("int", Data::Int(i)) => Some(Value::from(*i)),
+        ("int", Data::Float(f)) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => {
+            Some(Value::from(*f as i64))
+        }
+        ("float", Data::Int(i)) => Number::from_f64(*i as f64).map(Value::Number),
+        ("float", Data::Float(f)) => Number::from_f64(*f).map(Value::Number),
+        ("bool", Data::Bool(b)) => Some(Value::Bool(*b)),
+        ("text", Data::Float(f)) if f.fract() == 0.0 => Some(Value::String(format!("{f:.0}"))),
+        ("text", Data::Error(_)) => None,
+        ("text", other) => Some(Value::String(other.to_string())),
+        _ => None,
+    }
+}
+
+/// `Sheet!B5` style reference from 0-based row and column indices
+fn cell_ref(sheet: &str, row: u32, col: u32) -> String {
+    let mut letters = Vec::new();
+    let mut n = col + 1;
+    while n > 0 {
+        let rem = (n - 1) % 26;
+        letters.push(b'A' + rem as u8);
+        n = (n - 1) / 26;
+    }
+    letters.reverse();
+    format!("{}!{}{}", sheet, String::from_utf8_lossy(&letters), row + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn table() -> TableConfig {
+        serde_json::from_value(json!({
+            "name": "io",
+            "primary_key": "id",
+            "columns": [
+                { "name": "id", "type": "text" },
+                { "name": "rack", "type": "int" },
+                { "name": "spare", "type": "bool" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn sheet(cells: &[&[Data]]) -> Range<Data> {
+        let mut range = Range::new((0, 0), (cells.len() as u32 - 1, 2));
+        for (r, row) in cells.iter().enumerate() {
+            for (c, cell) in row.iter().enumerate() {
+                range.set_value((r as u32, c as u32), cell.clone());
+            }
+        }
+        range
+    }
+
+    #[test]
+    fn converts_cells_and_locates_rows() {
+        let s = |v: &str| Data::String(v.to_string());
+        let range = sheet(&[
+            &[s("id"), s("rack"), s("spare")],
+            &[s("IO_1"), Data::Float(2.0), Data::Bool(true)],
+            &[Data::Empty, Data::Empty, Data::Empty],
+            &[Data::Float(7.0), s("3"), Data::Empty],
+        ]);
+
+        let (rows, layout) = parse_sheet("io", &range, &table()).unwrap();
+        assert_eq!(
+            Value::from(rows),
+            json!([
+                { "id": "IO_1", "rack": 2, "spare": true },
+                { "id": "7", "rack": 3 }
+            ])
+        );
+
+        let sources = SheetSources {
+            sheets: HashMap::from([("io".to_string(), layout)]),
+        };
+        assert_eq!(sources.locate("io", 1, Some("rack")).unwrap(), "io!B4");
+        assert_eq!(sources.locate("io", 1, None).unwrap(), "io!4:4");
+    }
+
+    #[test]
+    fn reports_cell_references() {
+        let s = |v: &str| Data::String(v.to_string());
+        let range = sheet(&[&[s("id"), s("rack")], &[s("IO_1"), Data::Float(1.5)]]);
+
+        let err = parse_sheet("io", &range, &table()).unwrap_err().to_string();
+        assert_eq!(err, "io!B2: column `rack` expected int, got `1.5`");
+        assert_eq!(cell_ref("io", 0, 27), "io!AB1");
+    }
+}
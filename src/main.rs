@@ -1,10 +1,15 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use load::{parse_data::DataFile, parse_tables::SchemaConfig};
+use std::collections::BTreeMap;
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 mod data;
 mod load;
+mod report;
 mod script;
+#[cfg(test)]
+mod testing;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -13,7 +18,15 @@ async fn main() -> Result<()> {
         .filter_level(log::LevelFilter::Trace)
         .init();
 
-    let mut project_loaded = false;
+    // Commands needing no loaded project also run as `traverse <command> <args>`
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some((cmd, rest)) = args.split_first() {
+        let rest: Vec<&str> = rest.iter().map(String::as_str).collect();
+        return run_standalone(cmd, &rest);
+    }
+
+    // Directory of the loaded project
+    let mut project: Option<PathBuf> = None;
 
     print!("$ ");
     io::stdout().flush().unwrap();
@@ -37,18 +50,67 @@ async fn main() -> Result<()> {
                 let path = match parts.next() {
                     Some(p) => Path::new(p),
                     None => {
-                        println!("Usage: load <path>");
+                        println!("Usage: load <path> [overlay...]");
+                        continue;
+                    }
+                };
+                let overlays: Vec<&str> = parts.collect();
+
+                if load_project(path, &overlays).await.is_some() {
+                    project = Some(path.to_path_buf());
+                }
+            }
+
+            "open" => {
+                let (path, file) = match (parts.next(), parts.next()) {
+                    (Some(p), Some(f)) => (Path::new(p), Path::new(f)),
+                    _ => {
+                        println!("Usage: open <path> <file.sqlite>");
+                        continue;
+                    }
+                };
+
+                if open_project(path, file).await.is_some() {
+                    project = Some(path.to_path_buf());
+                }
+            }
+
+            "save" => {
+                if project.is_none() {
+                    println!("No project loaded. Use `load <path>` first.");
+                    continue;
+                }
+                let Some(file) = parts.next() else {
+                    println!("Usage: save <file.sqlite>");
+                    continue;
+                };
+
+                if let Err(e) = data::get_storage().save(Path::new(file)).await {
+                    log::error!("{e}");
+                }
+            }
+
+            "export" => {
+                if project.is_none() {
+                    println!("No project loaded. Use `load <path>` first.");
+                    continue;
+                }
+                let (format, output) = match (parts.next(), parts.next()) {
+                    (Some(f), Some(o)) => (f, Path::new(o)),
+                    _ => {
+                        println!("Usage: export <json|pretty|csv> <output> [query]");
                         continue;
                     }
                 };
+                let query = parts.collect::<Vec<_>>().join(" ");
 
-                if load_project(path).await.is_some() {
-                    project_loaded = true;
+                if let Err(e) = export(format, output, &query).await {
+                    log::error!("{e}");
                 }
             }
 
             "run" => {
-                if !project_loaded {
+                if project.is_none() {
                     println!("No project loaded. Use `load <path>` first.");
                     continue;
                 }
@@ -61,13 +123,82 @@ async fn main() -> Result<()> {
                     }
                 };
 
-                match script::Script::load(path) {
-                    Some(script) => {
-                        if let Err(e) = script.run(data::get_storage()).await {
-                            log::error!("{e}");
-                        }
+                if let Some(script) = script::Script::load(path)
+                    && let Err(e) = script.run(data::get_storage()).await
+                {
+                    log::error!("{e}");
+                }
+            }
+
+            "schema" => {
+                if project.is_none() {
+                    println!("No project loaded. Use `load <path>` first.");
+                    continue;
+                }
+
+                print_schema(data::get_storage(), parts.next());
+            }
+
+            "impact" => {
+                let Some(dir) = &project else {
+                    println!("No project loaded. Use `load <path>` first.");
+                    continue;
+                };
+                let args: Vec<&str> = parts.collect();
+                if args.is_empty() {
+                    println!("Usage: impact <table>:<key>... | impact --from <old-project>");
+                    continue;
+                }
+
+                if let Err(e) = print_impact(dir, &args).await {
+                    log::error!("{e}");
+                }
+            }
+
+            "refs" => {
+                if project.is_none() {
+                    println!("No project loaded. Use `load <path>` first.");
+                    continue;
+                }
+                let Some(row) = parts.next() else {
+                    println!("Usage: refs <table>:<key>");
+                    continue;
+                };
+
+                if let Err(e) = print_refs(data::get_storage(), row).await {
+                    log::error!("{e}");
+                }
+            }
+
+            "unreferenced" => {
+                if project.is_none() {
+                    println!("No project loaded. Use `load <path>` first.");
+                    continue;
+                }
+
+                if let Err(e) = print_unreferenced(data::get_storage(), parts.next()).await {
+                    log::error!("{e}");
+                }
+            }
+
+            "effective" => {
+                let (path, overlay) = match (parts.next(), parts.next()) {
+                    (Some(p), Some(o)) => (Path::new(p), o),
+                    _ => {
+                        println!("Usage: effective <path> <overlay> [table]");
+                        continue;
                     }
-                    None => {}
+                };
+
+                if let Err(e) = print_effective(path, overlay, parts.next()) {
+                    log::error!("{e}");
+                }
+            }
+
+            "diff" | "export-json-schema" => {
+                let args: Vec<&str> = parts.collect();
+                if let Err(e) = run_standalone(cmd, &args) {
+                    log::error!("{e}");
                 }
             }
 
@@ -75,7 +206,9 @@ async fn main() -> Result<()> {
 
             _ => {
                 println!("Unknown command: {cmd}");
-                println!("Commands: load <path>, run <script.json>, exit");
+                println!(
+                    "Commands: load <path> [overlay...], open <path> <file.sqlite>, save <file.sqlite>, export <json|pretty|csv> <output> [query], run <script.json>, schema [table], impact <table>:<key>... | --from <old>, refs <table>:<key>, unreferenced [table], effective <path> <overlay> [table], diff <old> <new>, export-json-schema <path> [output.json], exit"
+                );
             }
         }
 
@@ -85,15 +218,28 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
-async fn load_project(dir: &Path) -> Option<()> {
-    let config = match load::load_config(&Path::new(dir).join("schema.json")) {
-        Ok(v) => v,
-        Err(e) => {
-            log::error!("{e}");
-            return None;
-        }
-    };
-    let data = match load::load_data(&Path::new(dir).join("data.json"), &config) {
+/// Reads and validates the schema and data of a project directory.
+/// Overlays are file paths, or names of files in the project's `overlays` directory.
+fn read_project(dir: &Path, overlays: &[&str]) -> Result<(SchemaConfig, DataFile)> {
+    let config = load::load_config(&dir.join("schema.json"))?;
+    let data_path = ["data.json", "data.xlsx"]
+        .iter()
+        .map(|f| dir.join(f))
+        .find(|p| p.exists())
+        .unwrap_or_else(|| dir.join("data"));
+    let overlays: Vec<PathBuf> = overlays
+        .iter()
+        .map(|o| match o.ends_with(".json") {
+            true => PathBuf::from(o),
+            false => dir.join("overlays").join(format!("{o}.json")),
+        })
+        .collect();
+    let data = load::load_data(&data_path, &config, &overlays)?;
+    Ok((config, data))
+}
+
+async fn load_project(dir: &Path, overlays: &[&str]) -> Option<()> {
+    let (config, data) = match read_project(dir, overlays) {
         Ok(v) => v,
         Err(e) => {
             log::error!("{e}");
@@ -111,6 +257,224 @@ async fn load_project(dir: &Path) -> Option<()> {
     Some(())
 }
 
+async fn open_project(dir: &Path, file: &Path) -> Option<()> {
+    let result = match load::load_config(&dir.join("schema.json")) {
+        Ok(config) => data::open(config, file).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        log::error!("{e}");
+        return None;
+    }
+    log::info!("Successfully opened {:?}", file);
+    Some(())
+}
+
+/// Exports every table, or the result of `query` when given
+async fn export(format: &str, output: &Path, query: &str) -> Result<()> {
+    let format: data::ExportFormat = format.parse()?;
+    let storage = data::get_storage();
+    if query.is_empty() {
+        return storage.export(format, output).await;
+    }
+    let export = storage.export_query(query).await?;
+    log::info!("Exported {} rows to {:?}", export.rows.len(), output);
+    data::write_export(export, format, output)
+}
+
+fn print_effective(dir: &Path, overlay: &str, table: Option<&str>) -> Result<()> {
+    let (_, data) = read_project(dir, &[overlay])?;
+    let tables: BTreeMap<_, _> = data
+        .iter()
+        .filter(|(name, _)| table.is_none_or(|t| t == name.as_str()))
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&tables)?);
+    Ok(())
+}
+
+fn print_diff(old: &Path, new: &Path) -> Result<()> {
+    let (old_schema, old_data) = read_project(old, &[])?;
+    let (new_schema, new_data) = read_project(new, &[])?;
+    let diffs = report::diff((&old_schema, &old_data), (&new_schema, &new_data));
+    if diffs.is_empty() {
+        println!("No differences");
+    }
+    for d in diffs {
+        print!("{d}");
+    }
+    Ok(())
+}
+
+async fn print_impact(dir: &Path, args: &[&str]) -> Result<()> {
+    let storage = data::get_storage();
+    let (changed, removed) = match args {
+        ["--from", old] => {
+            // Both sides are read back from storage so values compare with the same types
+            let (old_schema, old_data) = read_project(Path::new(old), &[])?;
+            let old = data::Storage::new(old_schema, old_data).await?;
+            let (old_data, new_data) = (old.data().await?, storage.data().await?);
+
+            let mut changed = Vec::new();
+            let mut removed = Vec::new();
+            for d in report::diff((&old.schema, &old_data), (&storage.schema, &new_data)) {
+                let keys = d.added.iter().chain(d.modified.iter().map(|m| &m.key));
+                changed.extend(keys.map(|k| (d.table.clone(), k.clone())));
+                removed.extend(d.removed.iter().map(|k| (d.table.clone(), k.clone())));
+            }
+            (changed, removed)
+        }
+        rows => (
+            rows.iter()
+                .map(|r| storage.row_key(r))
+                .collect::<Result<Vec<_>>>()?,
+            Vec::new(),
+        ),
+    };
+
+    let scripts = load_scripts(&dir.join("scripts"))?;
+    for i in report::impact(storage, changed, &removed, &scripts).await? {
+        println!(
+            "{} (root {}, output {}): {} affected, {} removed",
+            i.script.display(),
+            i.root,
+            i.output.display(),
+            i.affected.len(),
+            i.removed.len()
+        );
+        for key in &i.affected {
+            println!("  {}", data::key_text(key));
+        }
+        for key in &i.removed {
+            println!("  - {}", data::key_text(key));
+        }
+    }
+    Ok(())
+}
+
+async fn print_refs(storage: &data::Storage, row: &str) -> Result<()> {
+    let row = storage.row_key(row)?;
+    println!("{}:{}", row.0, data::key_text(&row.1));
+    let refs = storage.where_used(row).await?;
+    if refs.is_empty() {
+        println!("  (not referenced)");
+    }
+    for r in refs {
+        println!(
+            "{}{}.{} <- {}",
+            "  ".repeat(r.depth),
+            r.table,
+            r.column,
+            data::key_text(&r.key)
+        );
+    }
+    Ok(())
+}
+
+async fn print_unreferenced(storage: &data::Storage, table: Option<&str>) -> Result<()> {
+    let mut found = false;
+    for t in storage
+        .nodes()
+        .filter(|t| table.is_none_or(|name| t.name == name))
+    {
+        found = true;
+        let Some(keys) = storage.unreferenced_rows(t).await? else {
+            continue;
+        };
+        println!("{}: {} unreferenced", t.name, keys.len());
+        for key in keys {
+            println!("  {}", data::key_text(&key));
+        }
+    }
+    if !found {
+        println!("Unknown table: {}", table.unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Every script in a project's scripts directory, sorted by file name
+fn load_scripts(dir: &Path) -> Result<Vec<(PathBuf, script::Script)>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "json"))
+        .collect();
+    paths.sort();
+    Ok(paths
+        .into_iter()
+        .filter_map(|p| script::Script::load(&p).map(|s| (p, s)))
+        .collect())
+}
+
+fn print_schema(storage: &data::Storage, table: Option<&str>) {
+    let mut found = false;
+    for t in storage
+        .nodes()
+        .filter(|t| table.is_none_or(|name| t.name == name))
+    {
+        found = true;
+        print!("{}", t.name);
+        if !t.extends.is_empty() {
+            print!(" extends {}", t.extends.join(", "));
+        }
+        if let Some(label) = &t.label {
+            print!(" \"{label}\"");
+        }
+        if let Some(description) = &t.description {
+            print!(" - {description}");
+        }
+        println!();
+
+        for c in &t.columns {
+            print!("  {}: {}", c.name, c.col_type);
+            if c.name == t.primary_key {
+                print!(" (key)");
+            }
+            if let Some(fk) = t.foreign_keys.iter().find(|fk| fk.column == c.name) {
+                print!(" -> {}.{}", fk.references.table, fk.references.column);
+            }
+            if let Some(unit) = &c.unit {
+                print!(" [{unit}]");
+            }
+            if let Some(label) = &c.label {
+                print!(" \"{label}\"");
+            }
+            if let Some(description) = &c.description {
+                print!(" - {description}");
+            }
+            println!();
+        }
+    }
+    if !found {
+        println!("Unknown table: {}", table.unwrap_or_default());
+    }
+}
+
+/// Commands that work on project directories rather than the loaded storage
+fn run_standalone(cmd: &str, args: &[&str]) -> Result<()> {
+    match (cmd, args) {
+        ("diff", [old, new]) => print_diff(Path::new(old), Path::new(new)),
+        ("diff", _) => Err(anyhow!("Usage: diff <old-project> <new-project>")),
+        ("export-json-schema", [dir]) => {
+            let dir = Path::new(dir);
+            export_json_schema(dir, &dir.join("data.schema.json"))
+        }
+        ("export-json-schema", [dir, output]) => {
+            export_json_schema(Path::new(dir), Path::new(output))
+        }
+        ("export-json-schema", _) => Err(anyhow!("Usage: export-json-schema <path> [output.json]")),
+        _ => Err(anyhow!(
+            "Unknown command: {cmd}, only diff and export-json-schema run outside the prompt"
+        )),
+    }
+}
+
+fn export_json_schema(dir: &Path, output: &Path) -> Result<()> {
+    let config = load::load_config(&dir.join("schema.json"))?;
+    let doc = load::export_json_schema(&config);
+    std::fs::write(output, serde_json::to_string_pretty(&doc)?)?;
+    log::info!("Wrote JSON Schema to {:?}", output);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -120,11 +484,23 @@ mod tests {
         env_logger::Builder::new()
             .filter_level(log::LevelFilter::Trace)
             .init();
-        let test_path = Path::new("D:\\repo\\traverse\\tests\\test_dir");
+        let test_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("test_dir");
 
-        load_project(&test_path).await.unwrap();
-        let script_path = &test_path.join("scripts").join("valve_io.json");
-        let script = script::Script::load(script_path).unwrap();
+        load_project(&test_path, &[]).await.unwrap();
+        // Rendered into a temporary project, the example output stays untouched
+        let script =
+            std::fs::read_to_string(test_path.join("scripts").join("valve_io.json")).unwrap();
+        let dir = testing::TempDir::new("valve_io", &[("scripts/valve_io.json", script)]);
+        let script =
+            script::Script::load(&dir.path().join("scripts").join("valve_io.json")).unwrap();
         script.run(data::get_storage()).await.unwrap();
+
+        let out = std::fs::read_to_string(script.output()).unwrap();
+        assert_eq!(
+            out,
+            std::fs::read_to_string(test_path.join("output").join("valve_io.json")).unwrap()
+        );
     }
 }
@@ -0,0 +1,193 @@
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::data::key_text;
+use crate::load::parse_data::{DataFile, Row};
+use crate::load::parse_tables::SchemaConfig;
+
+/// Rows added, removed and modified in one table, keyed by primary key
+#[derive(Debug, Default)]
+pub struct TableDiff {
+    pub table: String,
+    pub added: Vec<Value>,
+    pub removed: Vec<Value>,
+    pub modified: Vec<RowChange>,
+}
+
+#[derive(Debug)]
+pub struct RowChange {
+    pub key: Value,
+    pub columns: Vec<ColumnChange>,
+}
+
+#[derive(Debug)]
+pub struct ColumnChange {
+    pub column: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+impl TableDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Compares two versions of a project's data, one entry per table that changed
+pub fn diff(old: (&SchemaConfig, &DataFile), new: (&SchemaConfig, &DataFile)) -> Vec<TableDiff> {
+    let tables: BTreeSet<&String> = old.1.keys().chain(new.1.keys()).collect();
+
+    tables
+        .into_iter()
+        .map(|table| {
+            let old_rows = keyed_rows(old.0, old.1, table);
+            let new_rows = keyed_rows(new.0, new.1, table);
+
+            let mut out = TableDiff {
+                table: table.clone(),
+                ..Default::default()
+            };
+            for (key, new_row) in &new_rows {
+                match old_rows.get(key) {
+                    None => out.added.push(key.clone()),
+                    Some(old_row) => {
+                        let columns = diff_row(old_row, new_row);
+                        if !columns.is_empty() {
+                            out.modified.push(RowChange {
+                                key: key.clone(),
+                                columns,
+                            });
+                        }
+                    }
+                }
+            }
+            for key in old_rows.keys() {
+                if !new_rows.contains_key(key) {
+                    out.removed.push(key.clone());
+                }
+            }
+
+            out.added.sort_by(compare_keys);
+            out.removed.sort_by(compare_keys);
+            out.modified.sort_by(|a, b| compare_keys(&a.key, &b.key));
+            out
+        })
+        .filter(|d| !d.is_empty())
+        .collect()
+}
+
+fn keyed_rows<'a>(
+    schema: &SchemaConfig,
+    data: &'a DataFile,
+    table: &str,
+) -> HashMap<Value, &'a Row> {
+    let Some(pk) = schema
+        .tables
+        .iter()
+        .find(|t| t.name == table)
+        .map(|t| &t.primary_key)
+    else {
+        return HashMap::new();
+    };
+    data.get(table)
+        .into_iter()
+        .flatten()
+        .filter_map(|row| Some((row.get(pk)?.clone(), row)))
+        .collect()
+}
+
+fn diff_row(old: &Row, new: &Row) -> Vec<ColumnChange> {
+    let columns: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    columns
+        .into_iter()
+        .filter(|c| old.get(*c) != new.get(*c))
+        .map(|c| ColumnChange {
+            column: c.clone(),
+            old: old.get(c).cloned(),
+            new: new.get(c).cloned(),
+        })
+        .collect()
+}
+
+/// Orders numeric keys by value and everything else by its text
+pub fn compare_keys(a: &Value, b: &Value) -> Ordering {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        _ => key_text(a).cmp(&key_text(b)),
+    }
+}
+
+impl fmt::Display for TableDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} added, {} removed, {} modified",
+            self.table,
+            self.added.len(),
+            self.removed.len(),
+            self.modified.len()
+        )?;
+        for key in &self.added {
+            writeln!(f, "  + {}", key_text(key))?;
+        }
+        for key in &self.removed {
+            writeln!(f, "  - {}", key_text(key))?;
+        }
+        for change in &self.modified {
+            writeln!(f, "  ~ {}", key_text(&change.key))?;
+            for c in &change.columns {
+                let show =
+                    |v: &Option<Value>| v.as_ref().map_or("(none)".into(), |v| v.to_string());
+                writeln!(
+                    f,
+                    "      {}: {} -> {}",
+                    c.column,
+                    show(&c.old),
+                    show(&c.new)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reports_rows_by_primary_key() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [{
+                "name": "io",
+                "primary_key": "id",
+                "columns": [{ "name": "id", "type": "text" }, { "name": "rack", "type": "int" }]
+            }]
+        }))
+        .unwrap();
+        let old: DataFile = serde_json::from_value(json!({
+            "io": [{ "id": "A", "rack": 0 }, { "id": "B", "rack": 0 }, { "id": "C", "rack": 1 }]
+        }))
+        .unwrap();
+        let new: DataFile = serde_json::from_value(json!({
+            "io": [{ "id": "D" }, { "id": "C", "rack": 1 }, { "id": "A", "rack": 2 }]
+        }))
+        .unwrap();
+
+        let diffs = diff((&schema, &old), (&schema, &new));
+        assert_eq!(diffs.len(), 1);
+        let io = &diffs[0];
+        assert_eq!(io.added, [json!("D")]);
+        assert_eq!(io.removed, [json!("B")]);
+        assert_eq!(io.modified.len(), 1);
+        assert_eq!(io.modified[0].key, json!("A"));
+        assert_eq!(io.modified[0].columns[0].column, "rack");
+        assert_eq!(io.modified[0].columns[0].old, Some(json!(0)));
+        assert_eq!(io.modified[0].columns[0].new, Some(json!(2)));
+
+        assert!(diff((&schema, &old), (&schema, &old)).is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use crate::data::{FetchRoot, ROOT_KEY, RowKey, Storage, column_value};
+use crate::report::diff::compare_keys;
+use crate::script::Script;
+
+/// Root objects of a script whose generated output changes with the data
+#[derive(Debug)]
+pub struct ScriptImpact {
+    pub script: PathBuf,
+    pub output: PathBuf,
+    pub root: String,
+    pub affected: Vec<Value>,
+    pub removed: Vec<Value>,
+}
+
+/// Follows foreign keys in reverse from the changed rows and lists, per script,
+/// the root objects that reach one of them. Scripts with a `reverse_depth` also list rows
+/// that changed rows refer to, as their arrays change. Removed rows are matched against
+/// roots directly.
+pub async fn impact(
+    storage: &Storage,
+    changed: Vec<RowKey>,
+    removed: &[RowKey],
+    scripts: &[(PathBuf, Script)],
+) -> Result<Vec<ScriptImpact>> {
+    // Rows reaching a changed row, per reverse depth of the scripts
+    let mut by_depth: HashMap<usize, HashSet<RowKey>> = HashMap::new();
+
+    let mut out = Vec::new();
+    for (path, script) in scripts {
+        let fetch = match FetchRoot::parse(script.fetch()) {
+            Ok(fetch) => fetch,
+            Err(e) => {
+                log::warn!("{:?}: {e}, skipping", path);
+                continue;
+            }
+        };
+        let Some(root) = storage.node(&fetch.table) else {
+            log::warn!(
+                "{:?}: root table '{}' not in schema, skipping",
+                path,
+                fetch.table
+            );
+            continue;
+        };
+
+        let reached = match by_depth.entry(script.reverse_depth()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let start = storage.referenced_rows(&changed, *e.key()).await?;
+                e.insert(storage.reverse_closure(start).await?)
+            }
+        };
+
+        let fetched = fetched_keys(storage, &fetch, &root.primary_key).await;
+        let mut affected: Vec<Value> = reached
+            .iter()
+            .filter(|(t, k)| *t == root.name && fetched.as_ref().is_none_or(|f| f.contains(k)))
+            .map(|(_, k)| k.clone())
+            .collect();
+        affected.sort_by(compare_keys);
+
+        let mut gone: Vec<Value> = removed
+            .iter()
+            .filter(|(t, _)| *t == root.name)
+            .map(|(_, k)| k.clone())
+            .collect();
+        gone.sort_by(compare_keys);
+
+        out.push(ScriptImpact {
+            script: path.clone(),
+            output: script.output().to_path_buf(),
+            root: root.name.clone(),
+            affected,
+            removed: gone,
+        });
+    }
+    Ok(out)
+}
+
+/// Keys of the root rows selected by a fetch, `None` when the fetch does not expose the key
+async fn fetched_keys(storage: &Storage, fetch: &FetchRoot, pk: &str) -> Option<HashSet<Value>> {
+    let sql = format!("SELECT {} FROM ({})", ROOT_KEY, fetch.select_key(pk));
+    match sqlx::query(&sql).fetch_all(&storage.pool).await {
+        Ok(rows) => Some(rows.iter().map(|r| column_value(r, 0)).collect()),
+        Err(e) => {
+            log::debug!("Could not narrow impact to fetched rows: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load::parse_tables::SchemaConfig;
+    use crate::testing::{TempDir, schema};
+    use serde_json::json;
+
+    /// Scripts written to the `scripts` directory of a temporary project
+    fn scripts(name: &str, files: &[(&str, Value)]) -> TempDir {
+        let files: Vec<(String, String)> = files
+            .iter()
+            .map(|(file, script)| {
+                let mut script = script.clone();
+                script["act"] = json!("");
+                (format!("scripts/{file}"), script.to_string())
+            })
+            .collect();
+        TempDir::new(name, &files)
+    }
+
+    fn load(dir: &TempDir, name: &str) -> (PathBuf, Script) {
+        let path = dir.path().join("scripts").join(name);
+        let script = Script::load(&path).unwrap();
+        (path, script)
+    }
+
+    #[tokio::test]
+    async fn lists_affected_and_removed_roots_per_script() {
+        let schema: SchemaConfig = serde_json::from_value(json!({
+            "tables": [
+                {
+                    "name": "io",
+                    "primary_key": "id",
+                    "columns": [{ "name": "id", "type": "text" }, { "name": "rack", "type": "int" }]
+                },
+                {
+                    "name": "valves",
+                    "primary_key": "name",
+                    "columns": [{ "name": "name", "type": "text" }, { "name": "fb", "type": "text" }],
+                    "foreign_keys": [{ "column": "fb", "references": { "table": "io", "column": "id" } }]
+                }
+            ],
+            "views": [{
+                "name": "rack0_valves",
+                "sql": "SELECT v.name, v.fb FROM valves v JOIN io ON io.id = v.fb WHERE io.rack = 0",
+                "key": "name",
+                "foreign_keys": [{ "column": "fb", "references": { "table": "io", "column": "id" } }]
+            }]
+        }))
+        .unwrap();
+        let data = serde_json::from_value(json!({
+            "io": [{ "id": "A", "rack": 0 }, { "id": "B", "rack": 1 }],
+            "valves": [
+                { "name": "V1", "fb": "A" },
+                { "name": "V2", "fb": "B" },
+                { "name": "V3", "fb": "A" }
+            ]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+
+        let dir = scripts(
+            "impact",
+            &[
+                (
+                    "valves.json",
+                    json!({ "fetch": "SELECT * FROM valves WHERE name <> 'V3'" }),
+                ),
+                (
+                    "rack0.json",
+                    json!({ "fetch": "SELECT * FROM rack0_valves" }),
+                ),
+            ],
+        );
+        let scripts = [load(&dir, "valves.json"), load(&dir, "rack0.json")];
+        let changed = vec![storage.row_key("io:A").unwrap()];
+        let removed = [("valves".to_string(), json!("V9"))];
+
+        let result = impact(&storage, changed, &removed, &scripts).await.unwrap();
+        assert_eq!(result.len(), 2);
+
+        assert_eq!(result[0].root, "valves");
+        assert_eq!(result[0].affected, [json!("V1")]);
+        assert_eq!(result[0].removed, [json!("V9")]);
+
+        assert_eq!(result[1].root, "rack0_valves");
+        assert_eq!(result[1].affected, [json!("V1"), json!("V3")]);
+        assert!(result[1].removed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lists_roots_whose_reverse_arrays_change() {
+        let data = serde_json::from_value(json!({
+            "racks": [{ "id": 1 }, { "id": 2 }],
+            "io": [{ "id": "A", "rack": 1 }, { "id": "B", "rack": 2 }],
+            "valves": [{ "name": "V1", "fb": "A" }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema(), data).await.unwrap();
+
+        let io = |depth: usize| json!({ "fetch": "SELECT * FROM io", "reverse_depth": depth });
+        let racks =
+            |depth: usize| json!({ "fetch": "SELECT * FROM racks", "reverse_depth": depth });
+        let dir = scripts(
+            "impact_reverse",
+            &[
+                ("io0.json", io(0)),
+                ("io1.json", io(1)),
+                ("racks1.json", racks(1)),
+                ("racks2.json", racks(2)),
+            ],
+        );
+        let scripts = ["io0.json", "io1.json", "racks1.json", "racks2.json"].map(|n| load(&dir, n));
+        let changed = vec![storage.row_key("valves:V1").unwrap()];
+
+        let result = impact(&storage, changed, &[], &scripts).await.unwrap();
+        let affected: Vec<&[Value]> = result.iter().map(|i| i.affected.as_slice()).collect();
+        // `valves_by_fb` of io A lists V1, `io_by_rack` of rack 1 only does two levels down
+        assert_eq!(
+            affected,
+            [&[][..], &[json!("A")][..], &[][..], &[json!(1)][..]]
+        );
+    }
+}
@@ -0,0 +1,5 @@
+pub mod diff;
+pub mod impact;
+
+pub use diff::diff;
+pub use impact::impact;
@@ -1,16 +1,15 @@
 use std::path::Path;
 use std::path::PathBuf;
 
-use crate::data::Storage;
+use crate::data::{ScopeNode, ScopeObject, ScopeOptions, Storage, key_text, root_table_name};
+use crate::load::parse_tables::{ColumnConfig, TableConfig};
 use anyhow::Result;
 use serde::Deserialize;
 use serde_json::{Map, Value};
 use sqlx::Column;
 use sqlx::Row;
-use sqlx::sqlite::SqliteColumn;
 use sqlx::sqlite::SqliteRow;
-use sqlx::{Arguments, sqlite::SqliteArguments};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use tera::{Context, Tera};
 
 pub struct Script {
@@ -23,22 +22,39 @@ pub struct UserScript {
     fetch: String,
     #[serde(default)]
     mode: FetchMode,
+    /// Levels of rows referencing each row of a scope object, collected next to that
+    /// row's columns. Off by default.
+    #[serde(default)]
+    reverse_depth: usize,
+    #[serde(default)]
+    context: ContextMode,
+    /// Column naming the rows of a hierarchy in `hierarchy_path`
+    #[serde(default)]
+    path_column: Option<String>,
+    #[serde(flatten)]
+    scope: ScopeOptions,
     act: String,
 }
 
-#[derive(Debug, Deserialize)]
+/// Shape of the scope mode context
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextMode {
+    /// Related values under underscore joined names, `open_feedback_rack`
+    #[default]
+    Flat,
+    /// Foreign key columns replaced by the referenced row, `open_feedback.rack`
+    Nested,
+}
+
+#[derive(Debug, Deserialize, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum FetchMode {
     Raw,
+    #[default]
     Scope,
 }
 
-impl Default for FetchMode {
-    fn default() -> Self {
-        FetchMode::Scope
-    }
-}
-
 impl Script {
     pub fn load(path: &Path) -> Option<Self> {
         let raw_text = match std::fs::read_to_string(path) {
@@ -61,12 +77,31 @@ impl Script {
 
         Some(Self { data: s, output })
     }
+    pub fn fetch(&self) -> &str {
+        &self.data.fetch
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    /// Levels of rows referencing the scope rows that end up in the output
+    pub fn reverse_depth(&self) -> usize {
+        match self.data.mode {
+            FetchMode::Raw => 0,
+            FetchMode::Scope => self.data.reverse_depth,
+        }
+    }
+
     pub async fn run(&self, storage: &Storage) -> Result<()> {
-        let sql = match self.data.mode {
-            FetchMode::Raw => self.data.fetch.clone(),
-            FetchMode::Scope => storage.build_scope_query(&self.data.fetch)?,
-        };
-        let rows: Vec<SqliteRow> = storage.query(&sql).await?;
+        match self.data.mode {
+            FetchMode::Raw => self.run_raw(storage).await,
+            FetchMode::Scope => self.run_scope(storage).await,
+        }
+    }
+
+    async fn run_raw(&self, storage: &Storage) -> Result<()> {
+        let rows: Vec<SqliteRow> = storage.query(&self.data.fetch).await?;
         log::debug!("Query returned {} rows, rendering script:", rows.len());
 
         if rows.is_empty() {
@@ -76,65 +111,288 @@ impl Script {
         }
         self.clear()?;
 
-        match self.data.mode {
-            FetchMode::Raw => {
-                for row in rows {
-                    let mut tera = Tera::default();
-                    tera.add_raw_template("script", &self.data.act)?;
-
-                    let mut context = Context::new();
-
-                    for col in row.columns() {
-                        let name = col.name();
-
-                        if let Ok(v) = row.try_get::<String, _>(name) {
-                            context.insert(name, &v);
-                        } else if let Ok(v) = row.try_get::<i64, _>(name) {
-                            context.insert(name, &v);
-                        } else if let Ok(v) = row.try_get::<f64, _>(name) {
-                            context.insert(name, &v);
-                        }
-                    }
-                    let out = tera.render("script", &context)?;
-                    log::debug!("{out}");
-                    self.write(out)?;
+        let root = root_table_name(&self.data.fetch);
+        for row in rows {
+            let mut tera = Tera::default();
+            tera.add_raw_template("script", &self.data.act)?;
+
+            let mut context = Context::new();
+            let mut meta: Map<String, Value> = Map::new();
+
+            for col in row.columns() {
+                let name = col.name();
+
+                if let Some(table) = root.as_deref().and_then(|t| storage.node(t))
+                    && let Some(column) = table.columns.iter().find(|c| c.name == name)
+                {
+                    meta.insert(name.to_string(), column_meta(table, column));
                 }
-            }
-            FetchMode::Scope => {
-                let mut grouped: HashMap<String, Vec<SqliteRow>> = HashMap::new();
-                for row in rows {
-                    let root_id: String = row.try_get("root_id")?;
-                    grouped.entry(root_id.clone()).or_default().push(row);
+
+                if let Ok(v) = row.try_get::<String, _>(name) {
+                    context.insert(name, &v);
+                } else if let Ok(v) = row.try_get::<i64, _>(name) {
+                    context.insert(name, &v);
+                } else if let Ok(v) = row.try_get::<f64, _>(name) {
+                    context.insert(name, &v);
                 }
-                log::debug!("Grouped {} objects", grouped.len());
+            }
+            self.insert_meta(&mut context, &meta);
+            let out = tera.render("script", &context)?;
+            log::debug!("{out}");
+            self.write(out)?;
+        }
+        Ok(())
+    }
 
-                for (object_id, rows_for_object) in grouped {
-                    let mut nested_scope: Map<String, Value> = Map::new();
+    async fn run_scope(&self, storage: &Storage) -> Result<()> {
+        let objects = storage
+            .resolve_scope(&self.data.fetch, &self.data.scope)
+            .await?;
+        log::debug!(
+            "Scope resolved {} objects, rendering script:",
+            objects.len()
+        );
 
-                    for row in rows_for_object {
-                        let path: String = row.try_get("path")?;
-                        let value: String = row.try_get("value")?;
-                        nested_scope.insert(path, Value::String(value));
-                    }
-                    let mut context = Context::new();
-                    context.insert("object_id", &object_id);
-                    for (k, v) in nested_scope {
-                        context.insert(&k, &v);
+        if objects.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Query returned 0 rows, check your FETCH section"
+            ));
+        }
+        self.clear()?;
+
+        let root = root_table_name(&self.data.fetch).unwrap_or_default();
+        let children = self.children(storage, &objects).await?;
+        let ancestors = self.ancestors(storage, &objects).await?;
+        let root_columns: HashSet<&str> = storage
+            .node(&root)
+            .into_iter()
+            .flat_map(|t| t.columns.iter().map(|c| c.name.as_str()))
+            .collect();
+        let mut hidden = BTreeSet::new();
+
+        for object in objects {
+            for cycle in &object.cycles {
+                log::warn!(
+                    "{:?}: object {} reaches `{}` {} again through `{}`, not expanding it twice",
+                    self.output,
+                    object.key,
+                    cycle.table,
+                    cycle.key,
+                    cycle.prefix
+                );
+            }
+
+            let mut nested_scope = Map::new();
+            let mut meta: Map<String, Value> = Map::new();
+            let followed: HashSet<&str> = object.nodes.iter().map(|n| n.prefix.as_str()).collect();
+
+            for node in &object.nodes {
+                let Some(table) = storage.node(&node.table) else {
+                    continue;
+                };
+                let prefix = &node.prefix;
+                self.insert_row(&mut nested_scope, &mut meta, node, table, &followed);
+                let pk = &table.primary_key;
+                let key = node.row.get(pk).map(key_text).unwrap_or_default();
+
+                if let Some(arrays) = children.get(&table.name).and_then(|c| c.get(&key)) {
+                    for (name, list) in arrays {
+                        self.place(&mut nested_scope, prefix, name, list.clone());
                     }
-                    log::debug!("{:?}", context);
+                }
+
+                // Rows of a hierarchy also get their ancestors and path from the top
+                if let Some(by_key) = ancestors.get(&table.name) {
+                    let list = by_key.get(&key).cloned().unwrap_or_default();
+                    let column = self.path_column(table);
+                    let hierarchy_path = list
+                        .iter()
+                        .chain(std::iter::once(&*node.row))
+                        .map(|row| {
+                            let name = row.get(column).filter(|v| !v.is_null());
+                            name.or(row.get(pk)).map(key_text).unwrap_or_default()
+                        })
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    self.place(&mut nested_scope, prefix, "ancestors", list.into());
+                    self.place(
+                        &mut nested_scope,
+                        prefix,
+                        "hierarchy_path",
+                        hierarchy_path.into(),
+                    );
+                }
+            }
 
-                    let mut tera = Tera::default();
-                    tera.add_raw_template("script", &self.data.act)?;
-                    let out = tera.render("script", &context)?;
-                    log::debug!("{out}");
-                    self.write(out)?;
+            // Columns of the fetch win over traversed values of the same name,
+            // the root's own columns are the same values
+            for (name, value) in object.fetched {
+                if nested_scope.contains_key(&name) {
+                    if root_columns.contains(name.as_str()) {
+                        continue;
+                    }
+                    meta.shift_remove(&name);
+                    hidden.insert(name.clone());
                 }
+                nested_scope.insert(name, value);
+            }
+
+            let mut context = Context::new();
+            context.insert("object_id", &object.key);
+            for (k, v) in nested_scope {
+                context.insert(&k, &v);
             }
+            self.insert_meta(&mut context, &meta);
+            log::debug!("{:?}", context);
+
+            let mut tera = Tera::default();
+            tera.add_raw_template("script", &self.data.act)?;
+            let out = tera.render("script", &context)?;
+            log::debug!("{out}");
+            self.write(out)?;
+        }
+        for name in hidden {
+            log::warn!(
+                "{:?}: fetched column `{}` hides the traversed value of the same name",
+                self.output,
+                name
+            );
         }
         Ok(())
     }
+
+    /// Adds the columns of a row reached at `node.prefix` and their schema information.
+    /// In nested mode a foreign key in `followed` becomes the referenced row, in `meta` too.
+    fn insert_row(
+        &self,
+        scope: &mut Map<String, Value>,
+        meta: &mut Map<String, Value>,
+        node: &ScopeNode,
+        table: &TableConfig,
+        followed: &HashSet<&str>,
+    ) {
+        let prefix = &node.prefix;
+        for column in &table.columns {
+            let name = &column.name;
+            let value = node.row.get(name).cloned().unwrap_or_default();
+            match self.data.context {
+                ContextMode::Flat => {
+                    let path = match prefix.is_empty() {
+                        true => name.clone(),
+                        false => format!("{prefix}_{name}"),
+                    };
+                    meta.insert(path.clone(), column_meta(table, column));
+                    scope.insert(path, value);
+                }
+                ContextMode::Nested => {
+                    let path = match prefix.is_empty() {
+                        true => name.clone(),
+                        false => format!("{prefix}.{name}"),
+                    };
+                    if !followed.contains(path.as_str()) {
+                        insert_nested(meta, prefix, name, column_meta(table, column));
+                    }
+                    insert_nested(scope, prefix, name, value);
+                }
+            }
+        }
+    }
+
+    /// Rows referencing every row of the scope objects, `reverse_depth` levels deep, per table
+    async fn children(
+        &self,
+        storage: &Storage,
+        objects: &[ScopeObject],
+    ) -> Result<HashMap<String, HashMap<String, Map<String, Value>>>> {
+        let mut out = HashMap::new();
+        if self.data.reverse_depth == 0 {
+            return Ok(out);
+        }
+        let mut keys: HashMap<&str, Vec<Value>> = HashMap::new();
+        for node in objects.iter().flat_map(|o| &o.nodes) {
+            if let Some(table) = storage.node(&node.table)
+                && let Some(key) = node.row.get(&table.primary_key)
+            {
+                keys.entry(&node.table).or_default().push(key.clone());
+            }
+        }
+
+        for (table, mut keys) in keys {
+            keys.sort_by_key(key_text);
+            keys.dedup();
+            let children = storage
+                .children(table, &keys, self.data.reverse_depth)
+                .await?;
+            out.insert(table.to_string(), children);
+        }
+        Ok(out)
+    }
+
+    /// Ancestors of every row of a self-referencing table in the scope objects, per table
+    async fn ancestors(
+        &self,
+        storage: &Storage,
+        objects: &[ScopeObject],
+    ) -> Result<HashMap<String, HashMap<String, Vec<Map<String, Value>>>>> {
+        let mut keys: HashMap<&str, Vec<String>> = HashMap::new();
+        for node in objects.iter().flat_map(|o| &o.nodes) {
+            if storage.parent_key(&node.table).is_some()
+                && let Some(table) = storage.node(&node.table)
+                && let Some(key) = node.row.get(&table.primary_key)
+            {
+                keys.entry(&node.table).or_default().push(key_text(key));
+            }
+        }
+
+        let mut out = HashMap::new();
+        for (table, mut keys) in keys {
+            keys.sort();
+            keys.dedup();
+            let ancestors = storage.ancestors(table, &keys).await?;
+            out.insert(table.to_string(), ancestors);
+        }
+        Ok(out)
+    }
+
+    /// Column naming the rows of `table` in `hierarchy_path`: `path_column`, else a `label`
+    /// column, else the primary key. Rows without a name use their key.
+    fn path_column<'a>(&'a self, table: &'a TableConfig) -> &'a str {
+        let has = |name: &str| table.columns.iter().any(|c| c.name == name);
+        match &self.data.path_column {
+            Some(column) if has(column) => column,
+            _ if has("label") => "label",
+            _ => &table.primary_key,
+        }
+    }
+
+    /// Adds the schema information as `meta`, or as `_meta` when a value already uses that name
+    fn insert_meta(&self, context: &mut Context, meta: &Map<String, Value>) {
+        if context.contains_key("meta") {
+            log::warn!(
+                "{:?}: a value named `meta` hides the schema information, use `_meta` instead",
+                self.output
+            );
+            context.insert("_meta", meta);
+        } else {
+            context.insert("meta", meta);
+        }
+    }
+
+    /// Adds a value belonging to the row at `prefix`, next to that row's columns
+    fn place(&self, scope: &mut Map<String, Value>, prefix: &str, name: &str, value: Value) {
+        match self.data.context {
+            ContextMode::Flat if prefix.is_empty() => {
+                scope.insert(name.to_string(), value);
+            }
+            ContextMode::Flat => {
+                scope.insert(format!("{prefix}_{name}"), value);
+            }
+            ContextMode::Nested => insert_nested(scope, prefix, name, value),
+        }
+    }
+
     fn clear(&self) -> Result<()> {
-        std::fs::create_dir_all(&self.output.parent().expect("Impossible"))?;
+        std::fs::create_dir_all(self.output.parent().expect("Impossible"))?;
 
         std::fs::write(&self.output, "")
             .map_err(|e| anyhow::anyhow!("Failed to write to file: {}", e))
@@ -154,3 +412,216 @@ impl Script {
             .map_err(|e| anyhow::anyhow!("Failed to append to file: {}", e))
     }
 }
+
+/// Inserts `column` into the object reached by following the `.` separated foreign key
+/// columns of `prefix`. A foreign key column holding the referenced key becomes that object.
+fn insert_nested(scope: &mut Map<String, Value>, prefix: &str, column: &str, value: Value) {
+    let mut object = scope;
+    for step in prefix.split('.').filter(|s| !s.is_empty()) {
+        let entry = object.entry(step).or_insert(Value::Null);
+        if !entry.is_object() {
+            *entry = Value::Object(Map::new());
+        }
+        object = entry.as_object_mut().expect("object set above");
+    }
+    if !object.get(column).is_some_and(Value::is_object) {
+        object.insert(column.to_string(), value);
+    }
+}
+
+/// Schema information about a column and its table, exposed to templates under `meta`
+fn column_meta(table: &TableConfig, column: &ColumnConfig) -> Value {
+    let mut meta = Map::new();
+    meta.insert("table".into(), table.name.clone().into());
+    meta.insert("column".into(), column.name.clone().into());
+    meta.insert("type".into(), column.col_type.clone().into());
+    for (key, value) in [
+        ("label", &column.label),
+        ("description", &column.description),
+        ("unit", &column.unit),
+        ("table_label", &table.label),
+        ("table_description", &table.description),
+    ] {
+        if let Some(v) = value {
+            meta.insert(key.into(), v.clone().into());
+        }
+    }
+    Value::Object(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TempDir, schema};
+    use serde_json::json;
+
+    /// Output of a script run from the `scripts` directory of a temporary project
+    async fn render(name: &str, storage: &Storage, script: Value) -> String {
+        let dir = TempDir::new(name, &[("scripts/script.json", script.to_string())]);
+        let script = Script::load(&dir.path().join("scripts").join("script.json")).unwrap();
+        script.run(storage).await.unwrap();
+        std::fs::read_to_string(script.output()).unwrap()
+    }
+
+    #[test]
+    fn nests_values_under_foreign_keys() {
+        let mut scope = Map::new();
+        insert_nested(&mut scope, "", "name", json!("V101"));
+        insert_nested(&mut scope, "open_feedback", "rack", json!("0"));
+        insert_nested(&mut scope, "", "open_feedback", json!("IO_1"));
+        insert_nested(&mut scope, "open_feedback.rack", "id", json!("0"));
+        insert_nested(&mut scope, "closed_feedback", "rack", json!("1"));
+
+        assert_eq!(
+            Value::Object(scope),
+            json!({
+                "name": "V101",
+                "open_feedback": { "rack": { "id": "0" } },
+                "closed_feedback": { "rack": "1" }
+            })
+        );
+    }
+
+    #[test]
+    fn meta_describes_table_and_moves_aside_for_a_meta_column() {
+        let table: TableConfig = serde_json::from_value(json!({
+            "name": "io",
+            "primary_key": "id",
+            "label": "IO channel",
+            "columns": [{ "name": "rack", "type": "int", "unit": "slot" }]
+        }))
+        .unwrap();
+        let mut meta = Map::new();
+        meta.insert("rack".into(), column_meta(&table, &table.columns[0]));
+        assert_eq!(
+            meta["rack"],
+            json!({ "table": "io", "column": "rack", "type": "int", "unit": "slot", "table_label": "IO channel" })
+        );
+
+        let script = Script {
+            data: serde_json::from_value(json!({ "fetch": "SELECT * FROM io", "act": "" }))
+                .unwrap(),
+            output: PathBuf::from("out.txt"),
+        };
+        let mut context = Context::new();
+        context.insert("meta", "a column");
+        script.insert_meta(&mut context, &meta);
+        assert_eq!(context.get("meta"), Some(&json!("a column")));
+        assert_eq!(context.get("_meta"), Some(&Value::Object(meta)));
+    }
+
+    #[test]
+    fn nests_meta_like_values() {
+        let script = Script {
+            data: serde_json::from_value(
+                json!({ "fetch": "SELECT * FROM valves", "context": "nested", "act": "" }),
+            )
+            .unwrap(),
+            output: PathBuf::from("out.txt"),
+        };
+        let table = |value| serde_json::from_value::<TableConfig>(value).unwrap();
+        let valves = table(json!({
+            "name": "valves",
+            "primary_key": "name",
+            "columns": [{ "name": "name", "type": "text" }, { "name": "fb", "type": "text" }]
+        }));
+        let io = table(json!({
+            "name": "io",
+            "primary_key": "id",
+            "columns": [{ "name": "id", "type": "text" }, { "name": "rack", "type": "int", "label": "Rack" }]
+        }));
+        let node = |prefix: &str, table: &str, row: Value| ScopeNode {
+            prefix: prefix.to_string(),
+            table: table.to_string(),
+            row: std::sync::Arc::new(row.as_object().unwrap().clone()),
+        };
+        let root = node("", "valves", json!({ "name": "V1", "fb": "A" }));
+        let fb = node("fb", "io", json!({ "id": "A", "rack": 0 }));
+        let followed = HashSet::from(["", "fb"]);
+
+        let (mut scope, mut meta) = (Map::new(), Map::new());
+        script.insert_row(&mut scope, &mut meta, &root, &valves, &followed);
+        script.insert_row(&mut scope, &mut meta, &fb, &io, &followed);
+
+        assert_eq!(
+            Value::Object(scope),
+            json!({ "name": "V1", "fb": { "id": "A", "rack": 0 } })
+        );
+        assert_eq!(meta["fb"]["rack"]["label"], "Rack");
+        assert_eq!(meta["name"]["table"], "valves");
+        assert!(meta["fb"].get("table").is_none());
+    }
+
+    #[tokio::test]
+    async fn lists_referencing_rows_next_to_every_row() {
+        let data = serde_json::from_value(json!({
+            "racks": [{ "id": 1 }],
+            "io": [{ "id": "A", "rack": 1 }],
+            "valves": [{ "name": "V1", "fb": "A" }, { "name": "V2", "fb": "A" }]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema(), data).await.unwrap();
+        let act = "{{ name }}:{% for v in fb_valves_by_fb %}{{ v.name }}{% endfor %};";
+
+        let out = render(
+            "reverse_flat",
+            &storage,
+            json!({ "fetch": "SELECT * FROM valves", "reverse_depth": 1, "act": act }),
+        )
+        .await;
+        assert_eq!(out, "V1:V1V2;V2:V1V2;");
+
+        let nested = render(
+            "reverse_nested",
+            &storage,
+            json!({
+                "fetch": "SELECT * FROM valves WHERE name = 'V1'",
+                "reverse_depth": 1,
+                "context": "nested",
+                "act": "{{ fb.valves_by_fb | length }} {{ fb.rack.io_by_rack.0.id }}"
+            }),
+        )
+        .await;
+        assert_eq!(nested, "2 A");
+    }
+
+    #[tokio::test]
+    async fn builds_hierarchy_path_from_names() {
+        let schema = serde_json::from_value(json!({
+            "tables": [{
+                "name": "areas",
+                "primary_key": "id",
+                "columns": [
+                    { "name": "id", "type": "int" },
+                    { "name": "name", "type": "text" },
+                    { "name": "label", "type": "text" },
+                    { "name": "parent_id", "type": "int" }
+                ],
+                "foreign_keys": [{ "column": "parent_id", "references": { "table": "areas", "column": "id" } }]
+            }]
+        }))
+        .unwrap();
+        let data = serde_json::from_value(json!({
+            "areas": [
+                { "id": 1, "name": "Plant", "label": "Site" },
+                { "id": 2, "name": "Area1", "parent_id": 1 },
+                { "id": 3, "name": "Line2", "label": "L2", "parent_id": 2 }
+            ]
+        }))
+        .unwrap();
+        let storage = Storage::new(schema, data).await.unwrap();
+        let script = |path_column: Option<&str>| {
+            json!({
+                "fetch": "SELECT * FROM areas WHERE id = 3",
+                "path_column": path_column,
+                "act": "{{ hierarchy_path }}"
+            })
+        };
+
+        let named = render("path_named", &storage, script(Some("name"))).await;
+        assert_eq!(named, "Plant/Area1/Line2");
+        // Without the option a label column names the rows, the key stands in for a missing one
+        let labelled = render("path_label", &storage, script(None)).await;
+        assert_eq!(labelled, "Site/2/L2");
+    }
+}
@@ -0,0 +1,56 @@
+//! Fixtures shared by the unit tests
+
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
+use crate::load::parse_tables::SchemaConfig;
+
+/// Valves whose feedback `fb` is an io, which sits in a rack
+pub fn schema() -> SchemaConfig {
+    serde_json::from_value(json!({
+        "tables": [
+            { "name": "racks", "primary_key": "id", "columns": [{ "name": "id", "type": "int" }] },
+            {
+                "name": "io",
+                "primary_key": "id",
+                "columns": [{ "name": "id", "type": "text" }, { "name": "rack", "type": "int" }],
+                "foreign_keys": [{ "column": "rack", "references": { "table": "racks", "column": "id" } }]
+            },
+            {
+                "name": "valves",
+                "primary_key": "name",
+                "columns": [{ "name": "name", "type": "text" }, { "name": "fb", "type": "text" }],
+                "foreign_keys": [{ "column": "fb", "references": { "table": "io", "column": "id" } }]
+            }
+        ]
+    }))
+    .unwrap()
+}
+
+/// Temporary directory holding the given files, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` keeps the directories of tests running at the same time apart
+    pub fn new(name: &str, files: &[(impl AsRef<Path>, impl AsRef<str>)]) -> Self {
+        let dir = std::env::temp_dir().join(format!("traverse_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content.as_ref()).unwrap();
+        }
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}